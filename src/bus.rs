@@ -1,57 +1,457 @@
-use std::rc::Rc;
-use std::cell::RefCell;
 use timer::Timer;
-use io::IO;
+use cartridge::Cartridge;
+use ppu::Ppu;
+use dma::Dma;
+use serial::Serial;
+use model::Model;
+use joypad::{Joypad, Buttons};
+use trace::{Target, Level};
 use std::io::prelude::*;
 use std::fs::File;
 use constants::*;
 
-fn is_in(left_inc: usize, addr: usize, right_inc: usize) -> bool {
-    left_inc <= addr && addr <= right_inc
+// Component behind an IO register.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IoHandler {
+    // Nothing is mapped, reads 0xFF and writes are ignored.
+    Unmapped,
+    // Plain storage in the IO array.
+    Storage,
+    Joypad,
+    Serial,
+    Timer,
+    Ppu,
+    Dma,
+    Speed,
+    BootRom,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoRegister {
+    handler: IoHandler,
+    // Unused and write-only bits, they always read as 1.
+    unused: u8,
+}
+
+// Handler and unused bits of the IO register at the given address.
+fn io_register(addr: u16, model: Model) -> IoRegister {
+    let (handler, unused) = match addr {
+        REG_P1 => (IoHandler::Joypad, 0b1100_0000),
+        REG_SB => (IoHandler::Serial, 0x00),
+        REG_SC => (IoHandler::Serial, 0b0111_1110),
+        REG_DIV | REG_TIMA | REG_TMA => (IoHandler::Timer, 0x00),
+        REG_TAC => (IoHandler::Timer, 0b1111_1000),
+        REG_IF => (IoHandler::Storage, 0b1110_0000),
+        // Sound channel 1 (NR10 - NR14).
+        0xFF10 => (IoHandler::Storage, 0b1000_0000),
+        0xFF11 => (IoHandler::Storage, 0b0011_1111),
+        0xFF12 => (IoHandler::Storage, 0x00),
+        0xFF13 => (IoHandler::Storage, 0xFF),
+        0xFF14 => (IoHandler::Storage, 0b1011_1111),
+        // Sound channel 2 (NR21 - NR24).
+        0xFF16 => (IoHandler::Storage, 0b0011_1111),
+        0xFF17 => (IoHandler::Storage, 0x00),
+        0xFF18 => (IoHandler::Storage, 0xFF),
+        0xFF19 => (IoHandler::Storage, 0b1011_1111),
+        // Sound channel 3 (NR30 - NR34).
+        0xFF1A => (IoHandler::Storage, 0b0111_1111),
+        0xFF1B => (IoHandler::Storage, 0xFF),
+        0xFF1C => (IoHandler::Storage, 0b1001_1111),
+        0xFF1D => (IoHandler::Storage, 0xFF),
+        0xFF1E => (IoHandler::Storage, 0b1011_1111),
+        // Sound channel 4 (NR41 - NR44).
+        0xFF20 => (IoHandler::Storage, 0xFF),
+        0xFF21 | 0xFF22 => (IoHandler::Storage, 0x00),
+        0xFF23 => (IoHandler::Storage, 0b1011_1111),
+        // Sound control (NR50 - NR52).
+        0xFF24 | 0xFF25 => (IoHandler::Storage, 0x00),
+        0xFF26 => (IoHandler::Storage, 0b0111_0000),
+        // Wave pattern RAM.
+        0xFF30..=0xFF3F => (IoHandler::Storage, 0x00),
+        REG_STAT => (IoHandler::Ppu, 0b1000_0000),
+        REG_LCDC..=REG_LYC | REG_BGP..=REG_WX => (IoHandler::Ppu, 0x00),
+        REG_DMA => (IoHandler::Dma, 0x00),
+        REG_KEY1 if model == Model::Cgb => (IoHandler::Speed, 0b0111_1110),
+        REG_BOOT => (IoHandler::BootRom, 0xFF),
+        _ => (IoHandler::Unmapped, 0xFF),
+    };
+
+    IoRegister { handler, unused }
+}
+
+// What the CPU sees of the machine: the address space and the clock it advances with every access.
+pub trait Memory {
+    fn read_byte(&self, addr: usize) -> u8;
+    fn write_byte(&mut self, addr: usize, byte: u8);
+    fn register_cycles(&mut self, cycles: u16);
+    // T-cycles elapsed since power on.
+    fn cycles(&self) -> u64;
+    // STOP is left once a selected joypad line goes low.
+    fn is_any_joypad_line_low(&self) -> bool;
+    // Called by STOP, returns true if a CGB speed switch was performed.
+    fn switch_speed(&mut self) -> bool;
 }
 
 pub struct Bus {
-    mem: Rc<RefCell<[u8]>>,
+    // Shadows the start of the cartridge ROM until the boot program writes to 0xFF50.
+    boot_rom: Option<Vec<u8>>,
+    wram: Vec<u8>,
+    hram: Vec<u8>,
+    // Backing store of the IO registers no component owns yet.
+    io: Vec<u8>,
+    io_registers: Vec<IoRegister>,
+    ie: u8,
+    // T-cycles elapsed since power on.
+    cycles: u64,
+    // CGB double speed, the CPU, timer and DMA run twice as fast as the PPU.
+    double_speed: bool,
+    speed_switch_armed: bool,
+    pub cartridge: Cartridge,
     pub timer: Timer,
+    pub ppu: Ppu,
+    pub joypad: Joypad,
+    pub dma: Dma,
+    pub serial: Serial,
 }
 
 impl Bus {
-    pub fn new(mem: Rc<RefCell<[u8]>>, timer: Timer) -> Bus {
+    pub fn new(timer: Timer, cartridge: Cartridge, model: Model) -> Bus {
         Bus {
-            mem: mem,
-            timer: timer,
+            boot_rom: None,
+            wram: vec![0; MEM_MAP_INTERNAL_RAM_END - MEM_MAP_INTERNAL_RAM_START + 1],
+            hram: vec![0; MEM_MAP_HRAM_END - MEM_MAP_HRAM_START + 1],
+            io: vec![0; MEM_MAP_IO_END - MEM_MAP_IO_START + 1],
+            io_registers: (MEM_MAP_IO_START..=MEM_MAP_IO_END).map(|addr| io_register(addr as u16, model)).collect(),
+            ie: 0,
+            cycles: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            cartridge,
+            timer,
+            ppu: Ppu::new(),
+            joypad: Joypad::new(),
+            dma: Dma::new(),
+            serial: Serial::new(),
+        }
+    }
+
+    // Power cycle: memory, IO registers and every component start over, only the cartridge RAM survives and
+    // the host keeps holding the same buttons.
+    pub fn reset(&mut self) {
+        let buttons = self.joypad.buttons();
+
+        self.boot_rom = None;
+        for byte in self.wram.iter_mut().chain(self.hram.iter_mut()).chain(self.io.iter_mut()) {
+            *byte = 0;
         }
+        self.ie = 0;
+        self.cycles = 0;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
+        self.cartridge.reset();
+        self.timer = Timer::new();
+        self.ppu = Ppu::new();
+        self.joypad = Joypad::new();
+        self.joypad.set_buttons(buttons);
+        self.dma = Dma::new();
+        self.serial = Serial::new();
+    }
+
+    pub fn map_boot_rom(&mut self, mut boot_rom: Vec<u8>) {
+        boot_rom.resize(BOOT_ROM_SIZE, 0xFF);
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
     }
 
-    pub fn read_byte(&self, pos: usize) -> u8 {
-        self.mem.borrow()[pos]
+    // While OAM DMA runs the CPU only reaches HRAM, the IO registers and IE are internal to it as well.
+    fn is_blocked_by_dma(&self, addr: usize) -> bool {
+        self.dma.is_active() && addr < MEM_MAP_IO_START
+    }
+
+    pub fn read_byte(&self, addr: usize) -> u8 {
+        if self.is_blocked_by_dma(addr) {
+            return 0xFF;
+        }
+        self.read_mapped(addr)
     }
 
     pub fn write_byte(&mut self, addr: usize, byte: u8) {
-        // println!("WRITE --> {:#04X}", addr);
-        self.mem.borrow_mut()[addr] = byte;
-
-        if
-            is_in(MEM_MAP_INTERNAL_RAM_START, addr, MEM_MAP_INTERNAL_RAM_ECHO_END) ||
-            is_in(MEM_MAP_ECHO_OF_INTERNAL_RAM_START, addr, MEM_MAP_ECHO_OF_INTERNAL_RAM_END) {
-                let diff = MEM_MAP_ECHO_OF_INTERNAL_RAM_START - MEM_MAP_ECHO_OF_INTERNAL_RAM_START;
-                let offset: usize = if addr < MEM_MAP_ECHO_OF_INTERNAL_RAM_START {
-                    addr.wrapping_add(diff)
-                } else {
-                    addr.wrapping_sub(diff)
-                };
-                self.mem.borrow_mut()[offset] = byte
+        if self.is_blocked_by_dma(addr) {
+            return;
+        }
+        self.write_mapped(addr, byte);
+    }
+
+    fn read_mapped(&self, addr: usize) -> u8 {
+        match addr {
+            MEM_MAP_ROM_START..=MEM_MAP_ROM_END => match self.boot_rom {
+                Some(ref boot_rom) if addr < BOOT_ROM_SIZE => boot_rom[addr],
+                _ => self.cartridge.read_rom(addr),
+            },
+            MEM_MAP_VRAM_START..=MEM_MAP_VRAM_END => self.ppu.read_vram(addr),
+            MEM_MAP_EXTERNAL_RAM_START..=MEM_MAP_EXTERNAL_RAM_END => self.cartridge.read_ram(addr),
+            MEM_MAP_INTERNAL_RAM_START..=MEM_MAP_INTERNAL_RAM_END => self.wram[addr - MEM_MAP_INTERNAL_RAM_START],
+            // Echo of 0xC000 - 0xDDFF.
+            MEM_MAP_ECHO_OF_INTERNAL_RAM_START..=MEM_MAP_ECHO_OF_INTERNAL_RAM_END => self.wram[addr - MEM_MAP_ECHO_OF_INTERNAL_RAM_START],
+            MEM_MAP_OAM_START..=MEM_MAP_OAM_END => self.ppu.read_oam(addr),
+            // Unusable area.
+            MEM_MAP_UNUSABLE_START..=MEM_MAP_UNUSABLE_END => 0x00,
+            MEM_MAP_IO_START..=MEM_MAP_IO_END => self.read_io(addr),
+            MEM_MAP_HRAM_START..=MEM_MAP_HRAM_END => self.hram[addr - MEM_MAP_HRAM_START],
+            _ => self.ie,
+        }
+    }
+
+    fn write_mapped(&mut self, addr: usize, byte: u8) {
+        match addr {
+            // Writes to the ROM area program the MBC.
+            MEM_MAP_ROM_START..=MEM_MAP_ROM_END => self.cartridge.write_rom(addr, byte),
+            MEM_MAP_VRAM_START..=MEM_MAP_VRAM_END => self.ppu.write_vram(addr, byte),
+            MEM_MAP_EXTERNAL_RAM_START..=MEM_MAP_EXTERNAL_RAM_END => self.cartridge.write_ram(addr, byte),
+            MEM_MAP_INTERNAL_RAM_START..=MEM_MAP_INTERNAL_RAM_END => self.wram[addr - MEM_MAP_INTERNAL_RAM_START] = byte,
+            MEM_MAP_ECHO_OF_INTERNAL_RAM_START..=MEM_MAP_ECHO_OF_INTERNAL_RAM_END => self.wram[addr - MEM_MAP_ECHO_OF_INTERNAL_RAM_START] = byte,
+            MEM_MAP_OAM_START..=MEM_MAP_OAM_END => self.ppu.write_oam(addr, byte),
+            MEM_MAP_UNUSABLE_START..=MEM_MAP_UNUSABLE_END => { },
+            MEM_MAP_IO_START..=MEM_MAP_IO_END => self.write_io(addr, byte),
+            MEM_MAP_HRAM_START..=MEM_MAP_HRAM_END => self.hram[addr - MEM_MAP_HRAM_START] = byte,
+            _ => self.ie = byte,
+        }
+    }
+
+    fn read_io(&self, addr: usize) -> u8 {
+        let register = self.io_registers[addr - MEM_MAP_IO_START];
+        let byte = match register.handler {
+            IoHandler::Unmapped | IoHandler::BootRom => 0xFF,
+            IoHandler::Storage | IoHandler::Dma => self.io[addr - MEM_MAP_IO_START],
+            IoHandler::Joypad => self.joypad.read(),
+            IoHandler::Serial => self.serial.read(addr),
+            IoHandler::Timer => self.timer.read(addr),
+            IoHandler::Ppu => self.ppu.read_register(addr),
+            IoHandler::Speed => (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+        };
+
+        byte | register.unused
+    }
+
+    fn write_io(&mut self, addr: usize, byte: u8) {
+        log!(Target::Io, Level::Trace, "Write {:#04x} to IO register {:#06x}", byte, addr);
+
+        match self.io_registers[addr - MEM_MAP_IO_START].handler {
+            IoHandler::Unmapped => { },
+            IoHandler::Storage => self.io[addr - MEM_MAP_IO_START] = byte,
+            IoHandler::Joypad => if self.joypad.write(byte) {
+                self.request_interrupt(INT_JOYPAD);
+            },
+            IoHandler::Serial => self.serial.write(addr, byte),
+            // Writing DIV clears it.
+            IoHandler::Timer => self.timer.write(addr, byte),
+            // LY is read-only, STAT bits 0-2 too.
+            IoHandler::Ppu => self.ppu.write_register(addr, byte),
+            IoHandler::Dma => {
+                self.io[addr - MEM_MAP_IO_START] = byte;
+                self.dma.start(byte);
+                log!(Target::Bus, Level::Debug, "OAM DMA from {:#06x}", (byte as u16) << 8);
+            },
+            IoHandler::Speed => self.speed_switch_armed = byte & 1 == 1,
+            // Unmapping is permanent, only a reset brings the boot ROM back.
+            IoHandler::BootRom => if byte != 0 && self.boot_rom.is_some() {
+                log!(Target::Bus, Level::Info, "Boot ROM unmapped");
+                self.boot_rom = None;
+            },
+        }
+    }
+
+    // Loads the IO register values the boot ROM leaves behind. DMA only latches its value, the boot ROM
+    // leaves no transfer running.
+    pub fn load_post_boot_io(&mut self, values: &[(u16, u8)]) {
+        for &(addr, byte) in values {
+            if addr == REG_DMA {
+                self.io[addr as usize - MEM_MAP_IO_START] = byte;
+            } else {
+                self.write_byte(addr as usize, byte);
             }
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
     pub fn register_cycles(&mut self, cycles: u16) {
-        self.timer.inc(cycles as u64);
+        self.cycles += cycles as u64;
+
+        if self.timer.step(cycles) {
+            self.request_interrupt(INT_TIMER);
+        }
+
+        if self.serial.step(cycles) {
+            self.request_interrupt(INT_SERIAL);
+        }
+
+        for _ in 0..cycles / 4 {
+            if let Some((source, oam_addr)) = self.dma.tick() {
+                let byte = self.read_mapped(source);
+                self.ppu.write_oam_dma(oam_addr, byte);
+            }
+        }
+
+        let ppu_cycles = if self.double_speed { cycles / 2 } else { cycles };
+        let ppu_interrupts = self.ppu.step(ppu_cycles);
+        for &bit in [INT_VBLANK, INT_LCD_STAT].iter() {
+            if ppu_interrupts >> bit & 1 == 1 {
+                self.request_interrupt(bit);
+            }
+        }
     }
 
+    // Performs the CGB speed switch if KEY1 armed it, called by STOP. Returns true if the speed changed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        log!(Target::Bus, Level::Info, "Switched to {} speed", if self.double_speed { "double" } else { "normal" });
+        true
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        if self.joypad.set_buttons(buttons) {
+            self.request_interrupt(INT_JOYPAD);
+        }
+    }
+
+    // Sets the given bit of IF.
+    pub fn request_interrupt(&mut self, bit: u8) {
+        log!(Target::Interrupts, Level::Debug, "Interrupt requested: bit {}", bit);
+        self.io[REG_IF as usize - MEM_MAP_IO_START] |= 1 << bit;
+    }
+
+    // Dumps the whole address space as seen by the CPU.
     pub fn mem_dump(&mut self) {
         let mut f = File::create("/tmp/gameboy_emu_memdump.txt").unwrap();
-        let bytes = self.mem.borrow();
-        let _ = f.write_all(&*bytes);
+        let bytes: Vec<u8> = (0..=0xFFFF).map(|addr| self.read_byte(addr)).collect();
+        let _ = f.write_all(&bytes);
+    }
+}
+
+impl Memory for Bus {
+    fn read_byte(&self, addr: usize) -> u8 {
+        Bus::read_byte(self, addr)
     }
 
+    fn write_byte(&mut self, addr: usize, byte: u8) {
+        Bus::write_byte(self, addr, byte);
+    }
+
+    fn register_cycles(&mut self, cycles: u16) {
+        Bus::register_cycles(self, cycles);
+    }
+
+    fn cycles(&self) -> u64 {
+        Bus::cycles(self)
+    }
+
+    fn is_any_joypad_line_low(&self) -> bool {
+        self.joypad.is_any_line_low()
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        Bus::switch_speed(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::ROM_BANK_SIZE;
+
+    fn bus_with_rom(rom: Vec<u8>, model: Model) -> Bus {
+        Bus::new(Timer::new(), Cartridge::new(rom).unwrap(), model)
+    }
+
+    #[test]
+    fn boot_rom_shadows_the_cartridge_until_unmapped() {
+        let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+        for byte in rom[..0x0101].iter_mut() {
+            *byte = 0x11;
+        }
+        let mut bus = bus_with_rom(rom, Model::Dmg);
+        bus.map_boot_rom(vec![0x22; BOOT_ROM_SIZE]);
+
+        assert_eq!(bus.read_byte(0x0000), 0x22);
+        assert_eq!(bus.read_byte(0x00FF), 0x22);
+        assert_eq!(bus.read_byte(0x0100), 0x11);
+
+        // Only a nonzero write unmaps it.
+        bus.write_byte(REG_BOOT as usize, 0x00);
+        assert_eq!(bus.read_byte(0x0000), 0x22);
+        bus.write_byte(REG_BOOT as usize, 0x01);
+        assert!(!bus.is_boot_rom_mapped());
+        assert_eq!(bus.read_byte(0x0000), 0x11);
+        assert_eq!(bus.read_byte(0x00FF), 0x11);
+    }
+
+    #[test]
+    fn io_reads_set_the_unused_bits() {
+        let mut bus = bus_with_rom(vec![0; 2 * ROM_BANK_SIZE], Model::Dmg);
+
+        bus.write_byte(REG_IF as usize, 0x01);
+        assert_eq!(bus.read_byte(REG_IF as usize), 0xE1);
+        bus.write_byte(REG_TAC as usize, 0x05);
+        assert_eq!(bus.read_byte(REG_TAC as usize), 0xFD);
+    }
+
+    #[test]
+    fn div_write_clears_it() {
+        let mut bus = bus_with_rom(vec![0; 2 * ROM_BANK_SIZE], Model::Dmg);
+
+        bus.register_cycles(1024);
+        assert_eq!(bus.read_byte(REG_DIV as usize), 4);
+        bus.write_byte(REG_DIV as usize, 0x42);
+        assert_eq!(bus.read_byte(REG_DIV as usize), 0);
+    }
+
+    #[test]
+    fn ly_writes_are_ignored() {
+        let mut bus = bus_with_rom(vec![0; 2 * ROM_BANK_SIZE], Model::Dmg);
+        bus.write_byte(REG_LCDC as usize, 0x80);
+
+        // Three lines of 456 dots.
+        bus.register_cycles(3 * 456);
+        assert_eq!(bus.read_byte(REG_LY as usize), 3);
+        bus.write_byte(REG_LY as usize, 0x00);
+        assert_eq!(bus.read_byte(REG_LY as usize), 3);
+    }
+
+    #[test]
+    fn unmapped_registers_read_ff() {
+        let mut bus = bus_with_rom(vec![0; 2 * ROM_BANK_SIZE], Model::Dmg);
+
+        // KEY1 only exists on CGB.
+        for &addr in [0xFF03, 0xFF4C, REG_KEY1, 0xFF7F].iter() {
+            bus.write_byte(addr as usize, 0x00);
+            assert_eq!(bus.read_byte(addr as usize), 0xFF, "{:#06x}", addr);
+        }
+    }
+
+    #[test]
+    fn armed_speed_switch_flips_key1_bit_7() {
+        let mut bus = bus_with_rom(vec![0; 2 * ROM_BANK_SIZE], Model::Cgb);
+
+        assert!(!bus.switch_speed());
+        bus.write_byte(REG_KEY1 as usize, 0x01);
+        assert_eq!(bus.read_byte(REG_KEY1 as usize), 0x7F);
+
+        // The switch disarms KEY1.
+        assert!(bus.switch_speed());
+        assert_eq!(bus.read_byte(REG_KEY1 as usize), 0xFE);
+        bus.write_byte(REG_KEY1 as usize, 0x01);
+        assert!(bus.switch_speed());
+        assert_eq!(bus.read_byte(REG_KEY1 as usize), 0x7E);
+    }
 }
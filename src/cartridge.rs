@@ -0,0 +1,225 @@
+use mbc::{Mbc, RomOnly, Mbc1, Mbc2, Mbc3, Mbc5};
+use trace::{Target, Level};
+use std::fmt;
+
+// Cartridge header layout (0x0100 - 0x014F).
+const HEADER_END: usize = 0x014F;
+const HEADER_TITLE_START: usize = 0x0134;
+const HEADER_TITLE_END: usize = 0x0143;
+const HEADER_CGB_FLAG: usize = 0x0143;
+const HEADER_SGB_FLAG: usize = 0x0146;
+const HEADER_CARTRIDGE_TYPE: usize = 0x0147;
+const HEADER_ROM_SIZE: usize = 0x0148;
+const HEADER_RAM_SIZE: usize = 0x0149;
+const HEADER_VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const HEADER_GLOBAL_CHECKSUM_HI: usize = 0x014E;
+const HEADER_GLOBAL_CHECKSUM_LO: usize = 0x014F;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CartridgeType {
+    pub mbc: MbcKind,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_timer: bool,
+    pub has_rumble: bool,
+}
+
+impl CartridgeType {
+    fn from_byte(byte: u8) -> Result<CartridgeType, String> {
+        let (mbc, has_ram, has_battery, has_timer, has_rumble) = match byte {
+            0x00 => (MbcKind::RomOnly, false, false, false, false),
+            0x01 => (MbcKind::Mbc1, false, false, false, false),
+            0x02 => (MbcKind::Mbc1, true, false, false, false),
+            0x03 => (MbcKind::Mbc1, true, true, false, false),
+            0x05 => (MbcKind::Mbc2, false, false, false, false),
+            0x06 => (MbcKind::Mbc2, false, true, false, false),
+            0x08 => (MbcKind::RomOnly, true, false, false, false),
+            0x09 => (MbcKind::RomOnly, true, true, false, false),
+            0x0F => (MbcKind::Mbc3, false, true, true, false),
+            0x10 => (MbcKind::Mbc3, true, true, true, false),
+            0x11 => (MbcKind::Mbc3, false, false, false, false),
+            0x12 => (MbcKind::Mbc3, true, false, false, false),
+            0x13 => (MbcKind::Mbc3, true, true, false, false),
+            0x19 => (MbcKind::Mbc5, false, false, false, false),
+            0x1A => (MbcKind::Mbc5, true, false, false, false),
+            0x1B => (MbcKind::Mbc5, true, true, false, false),
+            0x1C => (MbcKind::Mbc5, false, false, false, true),
+            0x1D => (MbcKind::Mbc5, true, false, false, true),
+            0x1E => (MbcKind::Mbc5, true, true, false, true),
+            _ => return Err(format!("unsupported cartridge type {:#04X}", byte)),
+        };
+
+        Ok(CartridgeType { mbc, has_ram, has_battery, has_timer, has_rumble })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, String> {
+        if rom.len() <= HEADER_END {
+            return Err(format!("the ROM is too small to contain a cartridge header ({} bytes)", rom.len()));
+        }
+
+        let title = rom[HEADER_TITLE_START..HEADER_TITLE_END + 1]
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|byte| *byte as char)
+            .collect::<String>();
+
+        let rom_size = match rom[HEADER_ROM_SIZE] {
+            code @ 0x00..=0x08 => (2 * ROM_BANK_SIZE) << code,
+            code => return Err(format!("unsupported ROM size code {:#04X}", code)),
+        };
+
+        let ram_size = match rom[HEADER_RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            code => return Err(format!("unsupported RAM size code {:#04X}", code)),
+        };
+
+        Ok(Header {
+            title,
+            cgb_flag: rom[HEADER_CGB_FLAG],
+            sgb_flag: rom[HEADER_SGB_FLAG],
+            cartridge_type: CartridgeType::from_byte(rom[HEADER_CARTRIDGE_TYPE])?,
+            rom_size,
+            ram_size,
+            version: rom[HEADER_VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: (rom[HEADER_GLOBAL_CHECKSUM_HI] as u16) << 8 | rom[HEADER_GLOBAL_CHECKSUM_LO] as u16,
+        })
+    }
+}
+
+// Checksum over 0x0134 - 0x014C, verified by the boot ROM.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[HEADER_TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |acc, byte| acc.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+// Sum of every byte except the global checksum itself. Not verified by hardware.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(idx, _)| idx != HEADER_GLOBAL_CHECKSUM_HI && idx != HEADER_GLOBAL_CHECKSUM_LO)
+        .fold(0u16, |acc, (_, byte)| acc.wrapping_add(*byte as u16))
+}
+
+pub struct Cartridge {
+    header: Header,
+    mbc: Box<dyn Mbc>,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, String> {
+        let header = Header::parse(&rom)?;
+
+        if rom.len() < header.rom_size {
+            return Err(format!("the ROM is {} bytes but the header declares {} bytes", rom.len(), header.rom_size));
+        }
+
+        // Only the boot ROM checks the header checksum and nothing checks the global one, so a mismatch is just
+        // worth a warning. Skipping the boot ROM runs a cartridge the hardware would lock up on.
+        let checksum = header_checksum(&rom);
+        if checksum != header.header_checksum {
+            log!(Target::Bus, Level::Warn, "Header checksum is {:#04x} but the header declares {:#04x}", checksum, header.header_checksum);
+        }
+        let checksum = global_checksum(&rom);
+        if checksum != header.global_checksum {
+            log!(Target::Bus, Level::Warn, "Global checksum is {:#06x} but the header declares {:#06x}", checksum, header.global_checksum);
+        }
+
+        let ram_size = if header.cartridge_type.has_ram { header.ram_size } else { 0 };
+        let mbc: Box<dyn Mbc> = match header.cartridge_type.mbc {
+            MbcKind::RomOnly => Box::new(RomOnly::new(rom, ram_size)),
+            MbcKind::Mbc1 => Box::new(Mbc1::new(rom, ram_size)),
+            MbcKind::Mbc2 => Box::new(Mbc2::new(rom)),
+            MbcKind::Mbc3 => Box::new(Mbc3::new(rom, ram_size, header.cartridge_type.has_timer)),
+            MbcKind::Mbc5 => Box::new(Mbc5::new(rom, ram_size)),
+        };
+
+        Ok(Cartridge { header, mbc })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // 0x0000 - 0x7FFF.
+    pub fn read_rom(&self, addr: usize) -> u8 {
+        self.mbc.read_rom(addr)
+    }
+
+    // Writes to the ROM area program the MBC registers.
+    pub fn write_rom(&mut self, addr: usize, byte: u8) {
+        self.mbc.write_rom(addr, byte);
+    }
+
+    // 0xA000 - 0xBFFF.
+    pub fn read_ram(&self, addr: usize) -> u8 {
+        self.mbc.read_ram(addr)
+    }
+
+    pub fn write_ram(&mut self, addr: usize, byte: u8) {
+        self.mbc.write_ram(addr, byte);
+    }
+
+    // Resets the MBC registers, the RAM contents survive.
+    pub fn reset(&mut self) {
+        self.mbc.reset();
+    }
+
+    // External RAM worth saving, None without a battery.
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        let ram = self.mbc.ram();
+        if self.header.cartridge_type.has_battery && !ram.is_empty() { Some(ram) } else { None }
+    }
+
+    // Restores the external RAM from a save, which has to match the RAM size of the cartridge.
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        let ram = self.mbc.ram_mut();
+        if data.len() != ram.len() {
+            return Err(format!("the save is {} bytes but the cartridge has {} bytes of RAM", data.len(), ram.len()));
+        }
+
+        ram.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#?}", self.header)
+    }
+}
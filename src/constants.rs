@@ -1,31 +1,94 @@
 pub const STACK_TOP: u16 = 0xFFFE;
-// TOOD verify it's true
-pub const STACK_BOTTOM: u16 = 0xFF80;
 
+// Joypad (R/W).
+pub const REG_P1: u16 = 0xFF00;
+// Serial transfer data (R/W).
+pub const REG_SB: u16 = 0xFF01;
+// Serial transfer control (R/W).
+pub const REG_SC: u16 = 0xFF02;
 // Divider register.
 pub const REG_DIV: u16 = 0xFF04;
+// Timer counter (R/W).
+pub const REG_TIMA: u16 = 0xFF05;
+// Timer modulo (R/W).
+pub const REG_TMA: u16 = 0xFF06;
+// Timer control (R/W).
+pub const REG_TAC: u16 = 0xFF07;
 // Address of Intterrupt flag.
 pub const REG_IF: u16 = 0xFF0F;
 // LCD Control reg.
 pub const REG_LCDC: u16 = 0xFF40;
 // LCDC Status reg.
 pub const REG_STAT: u16 = 0xFF41;
+// Scroll Y (R/W).
+pub const REG_SCY: u16 = 0xFF42;
+// Scroll X (R/W).
+pub const REG_SCX: u16 = 0xFF43;
 // LCDC Y-Coordinate (R).
 pub const REG_LY: u16 = 0xFF44;
+// LY Compare (R/W).
+pub const REG_LYC: u16 = 0xFF45;
+// OAM DMA transfer and start address (W).
+pub const REG_DMA: u16 = 0xFF46;
+// BG Palette Data (R/W).
+pub const REG_BGP: u16 = 0xFF47;
+// Object Palette 0 Data (R/W).
+pub const REG_OBP0: u16 = 0xFF48;
+// Object Palette 1 Data (R/W).
+pub const REG_OBP1: u16 = 0xFF49;
+// Window Y Position (R/W).
+pub const REG_WY: u16 = 0xFF4A;
+// Window X Position minus 7 (R/W).
+pub const REG_WX: u16 = 0xFF4B;
+// CGB speed switch (R/W), bit 7 is the current speed, bit 0 arms the switch.
+pub const REG_KEY1: u16 = 0xFF4D;
+// Boot ROM disable, any nonzero write unmaps the boot ROM.
+pub const REG_BOOT: u16 = 0xFF50;
 // Interrupt Enable (R/W).
 pub const REG_IE: u16 = 0xFFFF;
 
+// Interrupt bits of IF and IE.
+pub const INT_VBLANK: u8 = 0;
+pub const INT_LCD_STAT: u8 = 1;
+pub const INT_TIMER: u8 = 2;
+pub const INT_SERIAL: u8 = 3;
+pub const INT_JOYPAD: u8 = 4;
+
 // Memory mapping.
-pub const ROM_BANK_ADDR_START: u16 = 0x0000;
-pub const ROM_BANK_ADDR_END: u16 = 0x3FFF;
+pub const BOOT_ROM_SIZE: usize = 0x100;
 
-pub const RAM_SIZE: usize = 0x1_0000;
+pub const MEM_MAP_ROM_START: usize =                  0x0000;
+pub const MEM_MAP_ROM_END: usize =                    0x7FFF;
+pub const MEM_MAP_VRAM_START: usize =                 0x8000;
+pub const MEM_MAP_VRAM_END: usize =                   0x9FFF;
+pub const MEM_MAP_EXTERNAL_RAM_START: usize =         0xA000;
+pub const MEM_MAP_EXTERNAL_RAM_END: usize =           0xBFFF;
 
 pub const MEM_MAP_ECHO_OF_INTERNAL_RAM_END: usize =   0xFDFF;
 pub const MEM_MAP_ECHO_OF_INTERNAL_RAM_START: usize = 0xE000;
 pub const MEM_MAP_INTERNAL_RAM_END: usize =           0xDFFF;
-pub const MEM_MAP_INTERNAL_RAM_ECHO_END: usize =      0xDDFF;
 pub const MEM_MAP_INTERNAL_RAM_START: usize =         0xC000;
+pub const MEM_MAP_OAM_START: usize =                  0xFE00;
+pub const MEM_MAP_OAM_END: usize =                    0xFE9F;
+pub const MEM_MAP_UNUSABLE_START: usize =             0xFEA0;
+pub const MEM_MAP_UNUSABLE_END: usize =               0xFEFF;
+pub const MEM_MAP_IO_START: usize =                   0xFF00;
+pub const MEM_MAP_IO_END: usize =                     0xFF7F;
+pub const MEM_MAP_HRAM_START: usize =                 0xFF80;
+pub const MEM_MAP_HRAM_END: usize =                   0xFFFE;
+
+// Timing.
+pub const CYCLES_PER_SECOND: u64 = 4_194_304;
+pub const CYCLES_PER_FRAME: u64 = 70_224;
+
+// Video.
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const TILE_MAP_0: usize = 0x9800;
+pub const TILE_MAP_1: usize = 0x9C00;
+pub const OAM_SPRITE_COUNT: usize = 40;
+// Gray level of the framebuffer shades 0 - 3, the palette reference screenshots use.
+pub const SHADE_GRAYSCALE: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
 
 // Instruction durations.
 pub const DURATION_MAINS: [u8; 256] = [
@@ -71,10 +134,10 @@ pub const DURATION_PREFIXED: [u8; 256] = [
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
-    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
-    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
-    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
-    8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,
+    8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8,
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
     8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8,
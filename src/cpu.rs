@@ -1,75 +1,72 @@
-use bus::{Bus};
+use bus::{Memory};
+use error::GameBoyError;
 use constants::*;
+use trace::{Target, Level};
 
-macro_rules! rl {
-    ($_self:expr, $reg:ident) => (
+// Applies an 8-bit ALU helper to a register and stores the result back.
+macro_rules! alu_r {
+    ($_self:expr, $op:ident, $reg:ident $(, $arg:expr)*) => (
         {
-            let new_carry = $_self.$reg >> 7;
-            $_self.$reg = ($_self.$reg << 1) | $_self.flag.c_carry as u8;
-            $_self.$reg = 1;
-            $_self.flag.c_carry = new_carry == 1;
-            $_self.flag.z_zero = $_self.$reg == 0;
-            $_self.flag.n_substract = false;
-            $_self.flag.h_half_carry = false;
+            let val = $_self.$reg;
+            $_self.$reg = $_self.$op(val $(, $arg)*);
         }
     )
 }
 
-macro_rules! dec_n {
-    ($_self:expr, $reg:ident) => (
+// Applies an 8-bit ALU helper to the byte at (HL) and writes the result back.
+macro_rules! alu_hl {
+    ($_self:expr, $bus:expr, $op:ident $(, $arg:expr)*) => (
         {
-            $_self.$reg = if $_self.$reg == 0 { 0xFF } else { $_self.$reg - 1 };
-            // TODO verify if this is a conditional set or always. Now it's always.
-            $_self.flag.z_zero = $_self.$reg == 0;
-            $_self.flag.n_substract = true;
-            // TODO verify if this a definite set or only when bit 3 == 1
-            $_self.flag.h_half_carry = $_self.$reg >> 4 & 1 == 0;
+            let addr = hi_lo_to_u16($_self.h, $_self.l) as usize;
+            let val = $_self.read_mem($bus, addr);
+            let res = $_self.$op(val $(, $arg)*);
+            $_self.write_mem($bus, addr, res);
         }
     )
 }
 
-macro_rules! inc_n {
-    ($_self:expr, $reg:ident) => (
+macro_rules! bit {
+    ($_self:expr, $bit:expr, $val:expr) => (
         {
-            $_self.$reg = if $_self.$reg == 0xFF { 0 } else { $_self.$reg + 1 };
-            // TODO verify if this is a conditional set or always. Now it's always.
-            $_self.flag.z_zero = $_self.$reg == 0;
+            let val = $val;
+            $_self.flag.z_zero = val >> $bit & 1 == 0;
             $_self.flag.n_substract = false;
-            // TODO verify if this a definite set or only when bit 3 == 1
-            $_self.flag.h_half_carry = $_self.$reg >> 3 & 1 == 0;
+            $_self.flag.h_half_carry = true;
         }
     )
 }
 
 macro_rules! inc_dd {
-    ($reg_hi:expr, $reg_lo:expr) => (
+    ($_self:expr, $bus:expr, $reg_hi:ident, $reg_lo:ident) => (
         {
-            let (hi, lo) = inc_dd($reg_hi, $reg_lo);
-            $reg_hi = hi;
-            $reg_lo = lo;
+            $_self.internal_cycle($bus);
+            let (hi, lo) = inc_dd($_self.$reg_hi, $_self.$reg_lo);
+            $_self.$reg_hi = hi;
+            $_self.$reg_lo = lo;
         }
     )
 }
 
 macro_rules! dec_dd {
-    ($reg_hi:expr, $reg_lo:expr) => (
+    ($_self:expr, $bus:expr, $reg_hi:ident, $reg_lo:ident) => (
         {
-            let (hi, lo) = dec_dd($reg_hi, $reg_lo);
-            $reg_hi = hi;
-            $reg_lo = lo;
+            $_self.internal_cycle($bus);
+            let (hi, lo) = dec_dd($_self.$reg_hi, $_self.$reg_lo);
+            $_self.$reg_hi = hi;
+            $_self.$reg_lo = lo;
         }
     )
 }
 
+// A substraction that only keeps the flags.
 macro_rules! cp {
     ($_self:expr, $cmp:expr) => (
         {
-            println!("--> CP {:#x} (acc) <-> {:#x} (cmp)", $_self.acc, $cmp);
-            let res = $_self.acc.wrapping_sub($cmp);
-            $_self.flag.z_zero = $_self.acc == $cmp;
-            $_self.flag.n_substract = true;
-            $_self.flag.h_half_carry = res >> 4 & 1 == 0;
-            $_self.flag.c_carry = $_self.acc < $cmp;
+            let cmp = $cmp;
+            log!(Target::Cpu, Level::Trace, "CP {:#x} (acc) <-> {:#x} (cmp)", $_self.acc, cmp);
+            let acc = $_self.acc;
+            $_self.sub_n(cmp, false);
+            $_self.acc = acc;
         }
     )
 }
@@ -83,6 +80,7 @@ macro_rules! call {
                 // Read address after data load so PC is set to next instruction.
                 // TODO review, http://www.devrs.com/gb/files/instr.txt does not mention SP adjustment here
                 let pc = $_self.pc;
+                $_self.internal_cycle($bus);
                 $_self.stack_push_d16(pc, $bus);
 
                 $_self.pc = hi_lo_to_u16(vhigh, vlow);
@@ -95,33 +93,59 @@ macro_rules! jr {
     ($_self:expr, $bus:expr, $cond:expr) => (
         {
             let addr = $_self.read_byte($bus);
-            if !$_self.flag.z_zero {
-                $_self.pc = (($_self.pc as i16) + ((addr as i8) as i16)) as u16;
+            if $cond {
+                $_self.internal_cycle($bus);
+                $_self.pc = $_self.pc.wrapping_add(addr as i8 as u16);
             }
         }
     )
 }
 
-macro_rules! interrupt {
-    ($_self:expr, $bus:expr, $int_addr:expr, $int_byte:expr, $int_offs:expr, $msg:expr) => (
+macro_rules! jp {
+    ($_self:expr, $bus:expr, $cond:expr) => (
         {
-            if $int_byte >> $int_offs & 1 == 1 {
-                println!($msg);
-
-                let int_disabled = $int_byte ^ (1 << $int_offs);
-                $bus.write_byte(REG_IF as usize, int_disabled);
-                println!("NEW IF {:#010X}", int_disabled);
+            let (vlow, vhigh) = $_self.read_low_high($bus);
+            if $cond {
+                $_self.internal_cycle($bus);
+                $_self.pc = hi_lo_to_u16(vhigh, vlow);
+            }
+        }
+    )
+}
 
-                let pc = $_self.pc;
-                $_self.stack_push_d16(pc, $bus);
-                $_self.pc = $int_addr;
-                $_self.ime_flag = false;
-                return;
+macro_rules! ret {
+    ($_self:expr, $bus:expr) => (
+        {
+            let (vlow, vhigh) = ($_self.stack_pop($bus), $_self.stack_pop($bus));
+            $_self.internal_cycle($bus);
+            $_self.pc = hi_lo_to_u16(vhigh, vlow);
+        }
+    );
+    // Evaluating the condition takes an extra M-cycle.
+    ($_self:expr, $bus:expr, $cond:expr) => (
+        {
+            $_self.internal_cycle($bus);
+            if $cond {
+                ret!($_self, $bus);
             }
         }
     )
 }
 
+macro_rules! rst {
+    ($_self:expr, $bus:expr, $addr:expr) => (
+        {
+            let pc = $_self.pc;
+            $_self.internal_cycle($bus);
+            $_self.stack_push_d16(pc, $bus);
+            $_self.pc = $addr;
+        }
+    )
+}
+
+// Names of the interrupt sources by IF/IE bit, the lower bit has the higher priority.
+const INTERRUPT_NAMES: [&str; 5] = ["V-Blank", "LCD STAT", "Timer", "Serial", "Joypad"];
+
 fn u16_to_hi_lo(dd: u16) -> (u8, u8) {
     ((dd >> 8) as u8, (dd & 0xFF) as u8)
 }
@@ -131,15 +155,11 @@ fn hi_lo_to_u16(hi:u8, lo:u8) -> u16 {
 }
 
 fn inc_dd(hi: u8, lo: u8) -> (u8, u8) {
-    let mut val: u16 = hi_lo_to_u16(hi, lo);
-    val += 1;
-    u16_to_hi_lo(val)
+    u16_to_hi_lo(hi_lo_to_u16(hi, lo).wrapping_add(1))
 }
 
 fn dec_dd(hi: u8, lo: u8) -> (u8, u8) {
-    let mut val: u16 = hi_lo_to_u16(hi, lo);
-    val -= 1;
-    u16_to_hi_lo(val)
+    u16_to_hi_lo(hi_lo_to_u16(hi, lo).wrapping_sub(1))
 }
 
 #[derive(Default, Debug)]
@@ -150,6 +170,40 @@ struct Flags {
     c_carry: bool,
 }
 
+impl Flags {
+    // Layout of the F register: Z N H C 0 0 0 0.
+    fn to_byte(&self) -> u8 {
+        (self.z_zero as u8) << 7 |
+        (self.n_substract as u8) << 6 |
+        (self.h_half_carry as u8) << 5 |
+        (self.c_carry as u8) << 4
+    }
+
+    fn from_byte(byte: u8) -> Flags {
+        Flags {
+            z_zero: byte >> 7 & 1 == 1,
+            n_substract: byte >> 6 & 1 == 1,
+            h_half_carry: byte >> 5 & 1 == 1,
+            c_carry: byte >> 4 & 1 == 1,
+        }
+    }
+}
+
+// Plain copy of the CPU registers, F holds the flags in their hardware layout.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
 #[derive(Default, Debug)]
 pub struct CPU {
     // Main register set.
@@ -171,6 +225,19 @@ pub struct CPU {
 
     // Interrupt master enable flag.
     ime_flag: bool,
+    // EI enables interrupts only after the following instruction.
+    ime_pending: bool,
+
+    // Waiting in HALT for IE & IF to become nonzero.
+    halted: bool,
+    // HALT executed with IME off and an interrupt pending, the next opcode byte is read twice.
+    halt_bug: bool,
+    // In STOP the clock is stopped until a joypad line goes low.
+    stopped: bool,
+    // LD B,B was executed since the last take_breakpoint.
+    breakpoint: bool,
+    // An illegal opcode hangs the CPU until reset, the rest of the machine keeps running.
+    locked: bool,
 }
 
 impl CPU {
@@ -178,18 +245,184 @@ impl CPU {
         CPU::default()
     }
 
+    // Power on state with IME off, clears HALT, STOP, a pending EI and a lockup too.
     pub fn reset(&mut self) {
+        *self = CPU::new();
         // Point to first instruction.
         self.pc = 0x0000;
-        self.ime_flag = true;
     }
 
-    pub fn next_instruction(&mut self, bus: &mut Bus)  {
+    // Puts the CPU in the state the boot ROM leaves it in, used when the boot ROM is skipped.
+    pub fn reset_post_boot(&mut self, regs: Registers) {
+        *self = CPU::new();
+        self.set_registers(regs);
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.acc,
+            f: self.flag.to_byte(),
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime_flag
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime_flag = ime;
+        self.ime_pending = false;
+    }
+
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.acc = regs.a;
+        self.flag = Flags::from_byte(regs.f);
+        self.b = regs.b;
+        self.c = regs.c;
+        self.d = regs.d;
+        self.e = regs.e;
+        self.h = regs.h;
+        self.l = regs.l;
+        self.sp = regs.sp;
+        self.pc = regs.pc;
+    }
+
+    pub fn next_instruction<M: Memory>(&mut self, bus: &mut M) -> Result<(), GameBoyError> {
+        if self.locked {
+            bus.register_cycles(4);
+            return Ok(());
+        }
+
+        // The clock keeps running while halted, the CPU leaves HALT as soon as an interrupt is pending
+        // and either services it or continues after the HALT depending on IME.
+        if self.stopped {
+            if bus.is_any_joypad_line_low() {
+                log!(Target::Cpu, Level::Debug, "Leaving STOP");
+                self.stopped = false;
+            }
+            return Ok(());
+        }
+
+        if self.halted {
+            bus.register_cycles(4);
+            if self.is_interrupt_pending(bus) {
+                log!(Target::Cpu, Level::Debug, "Leaving HALT");
+                self.halted = false;
+            }
+            return Ok(());
+        }
+
+        // Set by an EI executed right before this instruction.
+        let ei_delayed = self.ime_pending;
+
+        let start_cycles = bus.cycles();
+        let mut prefixed_opcode = None;
+
         let opcode = self.read_opcode(bus);
-        let mut cycles = 0u8;
-        println!("Read opcode {:#x} ({:#b}) at PC {:#x} ({})", opcode, opcode, self.pc - 1, self.pc - 1);
+        log!(Target::Cpu, Level::Trace, "Read opcode {:#x} ({:#b}) at PC {:#x}", opcode, opcode, self.pc.wrapping_sub(1));
 
         match opcode {
+            // ADC A,B.
+            0x88 => self.add_n(self.b, self.flag.c_carry),
+            // ADC A,C.
+            0x89 => self.add_n(self.c, self.flag.c_carry),
+            // ADC A,D.
+            0x8A => self.add_n(self.d, self.flag.c_carry),
+            // ADC A,E.
+            0x8B => self.add_n(self.e, self.flag.c_carry),
+            // ADC A,H.
+            0x8C => self.add_n(self.h, self.flag.c_carry),
+            // ADC A,L.
+            0x8D => self.add_n(self.l, self.flag.c_carry),
+            // ADC A,(HL).
+            0x8E => self.add_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize), self.flag.c_carry),
+            // ADC A,A.
+            0x8F => self.add_n(self.acc, self.flag.c_carry),
+            // ADC A,d8.
+            0xCE => {
+                let val = self.read_byte(bus);
+                self.add_n(val, self.flag.c_carry);
+            },
+
+            // ADD A,B.
+            0x80 => self.add_n(self.b, false),
+            // ADD A,C.
+            0x81 => self.add_n(self.c, false),
+            // ADD A,D.
+            0x82 => self.add_n(self.d, false),
+            // ADD A,E.
+            0x83 => self.add_n(self.e, false),
+            // ADD A,H.
+            0x84 => self.add_n(self.h, false),
+            // ADD A,L.
+            0x85 => self.add_n(self.l, false),
+            // ADD A,(HL).
+            0x86 => self.add_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize), false),
+            // ADD A,A.
+            0x87 => self.add_n(self.acc, false),
+            // ADD A,d8.
+            0xC6 => {
+                let val = self.read_byte(bus);
+                self.add_n(val, false);
+            },
+            // ADD HL,BC.
+            0x09 => {
+                self.internal_cycle(bus);
+                self.add_hl(hi_lo_to_u16(self.b, self.c));
+            },
+            // ADD HL,DE.
+            0x19 => {
+                self.internal_cycle(bus);
+                self.add_hl(hi_lo_to_u16(self.d, self.e));
+            },
+            // ADD HL,HL.
+            0x29 => {
+                self.internal_cycle(bus);
+                self.add_hl(hi_lo_to_u16(self.h, self.l));
+            },
+            // ADD HL,SP.
+            0x39 => {
+                self.internal_cycle(bus);
+                self.add_hl(self.sp);
+            },
+            // ADD SP,r8.
+            0xE8 => {
+                let offs = self.read_byte(bus);
+                self.internal_cycle(bus);
+                self.internal_cycle(bus);
+                self.sp = self.sp_plus_r8(offs);
+            },
+
+            // AND B.
+            0xA0 => self.and_n(self.b),
+            // AND C.
+            0xA1 => self.and_n(self.c),
+            // AND D.
+            0xA2 => self.and_n(self.d),
+            // AND E.
+            0xA3 => self.and_n(self.e),
+            // AND H.
+            0xA4 => self.and_n(self.h),
+            // AND L.
+            0xA5 => self.and_n(self.l),
+            // AND (HL).
+            0xA6 => self.and_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
+            // AND A.
+            0xA7 => self.and_n(self.acc),
+            // AND d8.
+            0xE6 => {
+                let val = self.read_byte(bus);
+                self.and_n(val);
+            },
+
             // CALL NZ,a16.
             0xC4 => call!(self, bus, !self.flag.z_zero),
             // CALL NC,a16.
@@ -201,6 +434,13 @@ impl CPU {
             // CALL a16.
             0xCD => call!(self, bus, true),
 
+            // CCF.
+            0x3F => {
+                self.flag.n_substract = false;
+                self.flag.h_half_carry = false;
+                self.flag.c_carry = !self.flag.c_carry;
+            },
+
             // CP B.
             0xB8 => cp!(self, self.b),
             // CP C.
@@ -223,78 +463,109 @@ impl CPU {
             },
             // CP (HL).
             0xBE => {
-                let cmp = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize);
+                let cmp = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize);
                 cp!(self, cmp);
             },
 
+            // CPL.
+            0x2F => {
+                self.acc = !self.acc;
+                self.flag.n_substract = true;
+                self.flag.h_half_carry = true;
+            },
+
+            // DAA.
+            0x27 => self.daa(),
+
             // DEC B.
-            0x05 => dec_n!(self, b),
+            0x05 => alu_r!(self, dec_n, b),
             // DEC D.
-            0x15 => dec_n!(self, d),
+            0x15 => alu_r!(self, dec_n, d),
             // DEC H.
-            0x25 => dec_n!(self, h),
+            0x25 => alu_r!(self, dec_n, h),
             // DEC C.
-            0x0D => dec_n!(self, c),
+            0x0D => alu_r!(self, dec_n, c),
             // DEC E.
-            0x1D => dec_n!(self, e),
+            0x1D => alu_r!(self, dec_n, e),
             // DEC L.
-            0x2D => dec_n!(self, l),
+            0x2D => alu_r!(self, dec_n, l),
             // DEC A.
-            0x3D => dec_n!(self, acc),
+            0x3D => alu_r!(self, dec_n, acc),
             // DEC BC.
-            0x0B => dec_dd!(self.b, self.c),
+            0x0B => dec_dd!(self, bus, b, c),
             // DEC DE.
-            0x1B => dec_dd!(self.d, self.e),
+            0x1B => dec_dd!(self, bus, d, e),
             // DEC HL.
-            0x2B => dec_dd!(self.h, self.l),
+            0x2B => dec_dd!(self, bus, h, l),
             // DEC (HL).
-            0x35 => {
-                let addr = hi_lo_to_u16(self.h, self.l) as usize;
-                let val = bus.read_byte(addr);
-                bus.write_byte(addr, val - 1);
-            },
+            0x35 => alu_hl!(self, bus, dec_n),
             // DEC SP.
-            0x3B => self.sp -= 1,
+            0x3B => {
+                self.internal_cycle(bus);
+                self.sp = self.sp.wrapping_sub(1);
+            },
 
             // DI.
-            // TODO check if it's a dedicated register or 0xFFFF (interrupt enable register).
-            0xF3 => self.ime_flag = false,
+            // Also cancels a pending EI.
+            0xF3 => {
+                self.ime_flag = false;
+                self.ime_pending = false;
+            },
 
             // EI.
-            // TODO check if it's a dedicated register or 0xFFFF (interrupt enable register).
-            0xFB => self.ime_flag = true,
+            // Takes effect after the next instruction, so EI; RET returns before an interrupt is serviced.
+            0xFB => self.ime_pending = true,
 
             // HALT.
-            0x76 => panic!("HALT."),
+            0x76 => {
+                if !self.is_interrupt_pending(bus) {
+                    self.halted = true;
+                } else if !self.ime_flag {
+                    log!(Target::Cpu, Level::Debug, "HALT bug at PC {:#x}", self.pc);
+                    self.halt_bug = true;
+                }
+            },
 
             // INC B.
-            0x04 => inc_n!(self, b),
+            0x04 => alu_r!(self, inc_n, b),
             // INC D.
-            0x14 => inc_n!(self, d),
+            0x14 => alu_r!(self, inc_n, d),
             // INC H.
-            0x24 => inc_n!(self, h),
+            0x24 => alu_r!(self, inc_n, h),
             // INC C.
-            0x0C => inc_n!(self, c),
+            0x0C => alu_r!(self, inc_n, c),
             // INC E.
-            0x1C => inc_n!(self, e),
+            0x1C => alu_r!(self, inc_n, e),
             // INC L.
-            0x2C => inc_n!(self, l),
+            0x2C => alu_r!(self, inc_n, l),
             // INC A.
-            0x3C => inc_n!(self, acc),
+            0x3C => alu_r!(self, inc_n, acc),
             // INC BC.
-            0x03 => inc_dd!(self.b, self.c),
+            0x03 => inc_dd!(self, bus, b, c),
             // INC DE.
-            0x13 => inc_dd!(self.d, self.e),
+            0x13 => inc_dd!(self, bus, d, e),
             // INC HL.
-            0x23 => inc_dd!(self.h, self.l),
+            0x23 => inc_dd!(self, bus, h, l),
             // INC SP.
-            0x33 => self.sp += 1,
-            // INC (HL).
-            0x34 => {
-                let addr = hi_lo_to_u16(self.h, self.l) as usize;
-                let val = bus.read_byte(addr);
-                bus.write_byte(addr, val + 1);
+            0x33 => {
+                self.internal_cycle(bus);
+                self.sp = self.sp.wrapping_add(1);
             },
+            // INC (HL).
+            0x34 => alu_hl!(self, bus, inc_n),
+
+            // JP NZ,a16.
+            0xC2 => jp!(self, bus, !self.flag.z_zero),
+            // JP NC,a16.
+            0xD2 => jp!(self, bus, !self.flag.c_carry),
+            // JP Z,a16.
+            0xCA => jp!(self, bus, self.flag.z_zero),
+            // JP C,a16.
+            0xDA => jp!(self, bus, self.flag.c_carry),
+            // JP a16.
+            0xC3 => jp!(self, bus, true),
+            // JP (HL).
+            0xE9 => self.pc = hi_lo_to_u16(self.h, self.l),
 
             // JR NZ,r8.
             0x20 => jr!(self, bus, !self.flag.z_zero),
@@ -331,33 +602,49 @@ impl CPU {
                 self.sp = hi_lo_to_u16(vhigh, vlow);
             },
 
+            // LD (a16),SP.
+            0x08 => {
+                let (lo, hi) = self.read_low_high(bus);
+                let addr = hi_lo_to_u16(hi, lo);
+                let (sp_hi, sp_lo) = u16_to_hi_lo(self.sp);
+                self.write_mem(bus, addr as usize, sp_lo);
+                self.write_mem(bus, addr.wrapping_add(1) as usize, sp_hi);
+            },
+            // LD SP,HL.
+            0xF9 => {
+                self.internal_cycle(bus);
+                self.sp = hi_lo_to_u16(self.h, self.l);
+            },
+            // LD HL,SP+r8.
+            0xF8 => {
+                let offs = self.read_byte(bus);
+                self.internal_cycle(bus);
+                let (hi, lo) = u16_to_hi_lo(self.sp_plus_r8(offs));
+                self.h = hi;
+                self.l = lo;
+            },
+
             // LD (BC),A.
-            0x02 => bus.write_byte(hi_lo_to_u16(self.b, self.c) as usize, self.acc),
+            0x02 => self.write_mem(bus, hi_lo_to_u16(self.b, self.c) as usize, self.acc),
             // LD (DE),A.
-            0x12 => bus.write_byte(hi_lo_to_u16(self.d, self.e) as usize, self.acc),
+            0x12 => self.write_mem(bus, hi_lo_to_u16(self.d, self.e) as usize, self.acc),
             // LD (HL+),A.
             0x22 => {
-                let mut addr = hi_lo_to_u16(self.h, self.l);
-                bus.write_byte(addr as usize, self.acc);
-
-                assert!(addr < 0xFFFF, "Address reg HL is max (0xFFFF), cannot be incremented");
-                addr += 1;
+                let addr = hi_lo_to_u16(self.h, self.l);
+                self.write_mem(bus, addr as usize, self.acc);
 
-                // TODO make it a func or macro.
-                self.h = (addr >> 8) as u8;
-                self.l = (addr & 0xFF) as u8;
+                let (h, l) = inc_dd(self.h, self.l);
+                self.h = h;
+                self.l = l;
             },
             // LD (HL-),A.
             0x32 => {
-                let mut addr = hi_lo_to_u16(self.h, self.l);
-                bus.write_byte(addr as usize, self.acc);
+                let addr = hi_lo_to_u16(self.h, self.l);
+                self.write_mem(bus, addr as usize, self.acc);
 
-                assert!(addr > 0, "Address reg HL is zero, cannot be decremented");
-                addr -= 1;
-
-                // TODO make it a func or macro.
-                self.h = (addr >> 8) as u8;
-                self.l = (addr & 0xFF) as u8;
+                let (h, l) = dec_dd(self.h, self.l);
+                self.h = h;
+                self.l = l;
             },
 
             // LD B,d8.
@@ -369,7 +656,7 @@ impl CPU {
             // LD (HL),d8.
             0x36 => {
                 let val = self.read_byte(bus);
-                bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, val)
+                self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, val)
             },
             // LD C,d8.
             0x0E => self.c = self.read_byte(bus),
@@ -381,36 +668,31 @@ impl CPU {
             0x3E => self.acc = self.read_byte(bus),
 
             // LD A,(BC).
-            0x0A => self.acc = bus.read_byte(hi_lo_to_u16(self.b, self.c) as usize),
+            0x0A => self.acc = self.read_mem(bus, hi_lo_to_u16(self.b, self.c) as usize),
             // LD A,(DE).
-            0x1A => self.acc = bus.read_byte(hi_lo_to_u16(self.d, self.e) as usize),
+            0x1A => self.acc = self.read_mem(bus, hi_lo_to_u16(self.d, self.e) as usize),
             // LD A,(HL+).
             0x2A => {
-                let mut addr = hi_lo_to_u16(self.h, self.l);
-                self.acc = bus.read_byte(addr as usize);
-
-                assert!(addr < 0xFFFF, "Address reg HL is max (0xFFFF), cannot be incremented");
-                addr += 1;
+                let addr = hi_lo_to_u16(self.h, self.l);
+                self.acc = self.read_mem(bus, addr as usize);
 
-                // TODO make it a func or macro.
-                self.h = (addr >> 8) as u8;
-                self.l = (addr & 0xFF) as u8;
+                let (h, l) = inc_dd(self.h, self.l);
+                self.h = h;
+                self.l = l;
             },
             // LD A,(HL-).
             0x3A => {
-                let mut addr = hi_lo_to_u16(self.h, self.l);
-                self.acc = bus.read_byte(addr as usize);
+                let addr = hi_lo_to_u16(self.h, self.l);
+                self.acc = self.read_mem(bus, addr as usize);
 
-                assert!(addr > 0, "Address reg HL is zero, cannot be decremented");
-                addr -= 1;
-
-                // TODO make it a func or macro.
-                self.h = (addr >> 8) as u8;
-                self.l = (addr & 0xFF) as u8;
+                let (h, l) = dec_dd(self.h, self.l);
+                self.h = h;
+                self.l = l;
             },
 
             // LD B,B.
-            0x40 => self.b = self.b,
+            // Test ROMs use it as a software breakpoint.
+            0x40 => self.breakpoint = true,
             // LD B,C.
             0x41 => self.b = self.c,
             // LD B,D.
@@ -422,14 +704,14 @@ impl CPU {
             // LD B,L.
             0x45 => self.b = self.l,
             // LD B,(HL).
-            0x46 => self.b = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x46 => self.b = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD B,A.
             0x47 => self.b = self.acc,
 
             // LD C,B.
             0x48 => self.c = self.b,
             // LD C,C.
-            0x49 => self.c = self.c,
+            0x49 => { },
             // LD C,D.
             0x4A => self.c = self.d,
             // LD C,E.
@@ -439,7 +721,7 @@ impl CPU {
             // LD C,L.
             0x4D => self.c = self.l,
             // LD C,(HL).
-            0x4E => self.c = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x4E => self.c = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD C,A.
             0x4F => self.c = self.acc,
 
@@ -448,7 +730,7 @@ impl CPU {
             // LD D,C.
             0x51 => self.d = self.c,
             // LD D,D.
-            0x52 => self.d = self.d,
+            0x52 => { },
             // LD D,E.
             0x53 => self.d = self.e,
             // LD D,H.
@@ -456,7 +738,7 @@ impl CPU {
             // LD D,L.
             0x55 => self.d = self.l,
             // LD D,(HL).
-            0x56 => self.d = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x56 => self.d = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD D,A.
             0x57 => self.d = self.acc,
 
@@ -467,13 +749,13 @@ impl CPU {
             // LD E,D.
             0x5A => self.e = self.d,
             // LD E,E.
-            0x5B => self.e = self.e,
+            0x5B => { },
             // LD E,H.
             0x5C => self.e = self.h,
             // LD E,L.
             0x5D => self.e = self.l,
             // LD E,(HL).
-            0x5E => self.e = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x5E => self.e = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD E,A.
             0x5F => self.e = self.acc,
 
@@ -486,11 +768,11 @@ impl CPU {
             // LD H,E.
             0x63 => self.h = self.e,
             // LD H,H.
-            0x64 => self.h = self.h,
+            0x64 => { },
             // LD H,L.
             0x65 => self.h = self.l,
             // LD H,(HL).
-            0x66 => self.h = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x66 => self.h = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD H,A.
             0x67 => self.h = self.acc,
 
@@ -505,26 +787,26 @@ impl CPU {
             // LD L,H.
             0x6C => self.l = self.h,
             // LD L,L.
-            0x6D => self.l = self.l,
+            0x6D => { },
             // LD L,(HL).
-            0x6E => self.l = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x6E => self.l = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD L,A.
             0x6F => self.l = self.acc,
 
             // LD (HL),B.
-            0x70 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.b),
+            0x70 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.b),
             // LD (HL),C.
-            0x71 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.c),
+            0x71 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.c),
             // LD (HL),D.
-            0x72 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.d),
+            0x72 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.d),
             // LD (HL),E.
-            0x73 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.e),
+            0x73 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.e),
             // LD (HL),H.
-            0x74 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.h),
+            0x74 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.h),
             // LD (HL),L.
-            0x75 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.l),
+            0x75 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.l),
             // LD (HL),A.
-            0x77 => bus.write_byte(hi_lo_to_u16(self.h, self.l) as usize, self.acc),
+            0x77 => self.write_mem(bus, hi_lo_to_u16(self.h, self.l) as usize, self.acc),
 
             // LD A,B.
             0x78 => self.acc = self.b,
@@ -539,714 +821,1260 @@ impl CPU {
             // LD A,L.
             0x7D => self.acc = self.l,
             // LD A,(HL).
-            0x7E => self.acc = bus.read_byte(hi_lo_to_u16(self.h, self.l) as usize),
+            0x7E => self.acc = self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize),
             // LD A,A.
-            0x7F => self.acc = self.acc,
+            0x7F => { },
 
             // LDH (n),A.
             0xE0 => {
                 let addr = self.read_byte(bus);
-                bus.write_byte((0xFF00 + (addr as u16)) as usize, self.acc);
+                self.write_mem(bus, (0xFF00 + (addr as u16)) as usize, self.acc);
             },
             // LD (C),A.
             0xE2 => {
                 // let offs = self.read_byte(bus);
-                // bus.write_byte((offs + self.c) as usize, self.acc);
+                // self.write_mem(bus, (offs + self.c) as usize, self.acc);
                 // There is some contradiciton here.
                 // http://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html mentions LD (C),A is a 2 byte op.
                 // However it doesn't refer to a loaded byte as well as http://gbdev.gg8.se/wiki/articles/Gameboy_Bootstrap_ROM
                 // says its a 1 byte op with fixed signing. We follow the latter now.
-                bus.write_byte((0xFF00 + (self.c as u16)) as usize, self.acc);
+                self.write_mem(bus, (0xFF00 + (self.c as u16)) as usize, self.acc);
             },
             // LD (a16),A.
             0xEA => {
                 let (lo, hi) = self.read_low_high(bus);
                 let addr = hi_lo_to_u16(hi, lo);
-                bus.write_byte(addr as usize, self.acc);
+                self.write_mem(bus, addr as usize, self.acc);
             },
+            // LD A,(a16).
+            0xFA => {
+                let (lo, hi) = self.read_low_high(bus);
+                self.acc = self.read_mem(bus, hi_lo_to_u16(hi, lo) as usize);
+            },
+            // LD A,(C).
+            0xF2 => self.acc = self.read_mem(bus, (0xFF00 + (self.c as u16)) as usize),
             // LDH A,(a8).
             0xF0 => {
                 let offs = self.read_byte(bus);
-                self.acc = bus.read_byte((0xFF00 | (offs as u16)) as usize);
-                // println!("LDH A a8 <- {:#010b} from {:#x}", self.acc, (0xFF00 | (offs as u16)));
-                // bus.mem_dump();
-                // panic!("Exit on mem dump.");
+                self.acc = self.read_mem(bus, (0xFF00 | (offs as u16)) as usize);
             },
 
             // NOP.
             0x00 => { },
 
+            // OR B.
+            0xB0 => self.or_n(self.b),
+            // OR C.
+            0xB1 => self.or_n(self.c),
+            // OR D.
+            0xB2 => self.or_n(self.d),
+            // OR E.
+            0xB3 => self.or_n(self.e),
+            // OR H.
+            0xB4 => self.or_n(self.h),
+            // OR L.
+            0xB5 => self.or_n(self.l),
+            // OR (HL).
+            0xB6 => self.or_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
+            // OR A.
+            0xB7 => self.or_n(self.acc),
+            // OR d8.
+            0xF6 => {
+                let val = self.read_byte(bus);
+                self.or_n(val);
+            },
+
             // POP BC.
             0xC1 => {
                 self.c = self.stack_pop(bus);
                 self.b = self.stack_pop(bus);
             },
+            // POP DE.
+            0xD1 => {
+                self.e = self.stack_pop(bus);
+                self.d = self.stack_pop(bus);
+            },
+            // POP HL.
+            0xE1 => {
+                self.l = self.stack_pop(bus);
+                self.h = self.stack_pop(bus);
+            },
+            // POP AF.
+            0xF1 => {
+                self.flag = Flags::from_byte(self.stack_pop(bus));
+                self.acc = self.stack_pop(bus);
+            },
 
             // Prefix CB.
             0xCB => {
                 let real_opcode = self.read_byte(bus);
-                self.exec_prefixed_instruction(real_opcode);
-                cycles += DURATION_PREFIXED[real_opcode as usize];
+                self.exec_prefixed_instruction(real_opcode, bus);
+                prefixed_opcode = Some(real_opcode);
             },
 
             // PUSH BC.
             0xC5 => {
                 let (b, c) = (self.b, self.c);
+                self.internal_cycle(bus);
                 self.stack_push(b, bus);
                 self.stack_push(c, bus);
             },
+            // PUSH DE.
+            0xD5 => {
+                let (d, e) = (self.d, self.e);
+                self.internal_cycle(bus);
+                self.stack_push(d, bus);
+                self.stack_push(e, bus);
+            },
+            // PUSH HL.
+            0xE5 => {
+                let (h, l) = (self.h, self.l);
+                self.internal_cycle(bus);
+                self.stack_push(h, bus);
+                self.stack_push(l, bus);
+            },
+            // PUSH AF.
+            0xF5 => {
+                let (acc, flag) = (self.acc, self.flag.to_byte());
+                self.internal_cycle(bus);
+                self.stack_push(acc, bus);
+                self.stack_push(flag, bus);
+            },
 
+            // RET NZ.
+            0xC0 => ret!(self, bus, !self.flag.z_zero),
+            // RET NC.
+            0xD0 => ret!(self, bus, !self.flag.c_carry),
+            // RET Z.
+            0xC8 => ret!(self, bus, self.flag.z_zero),
+            // RET C.
+            0xD8 => ret!(self, bus, self.flag.c_carry),
             // RET.
-            0xC9 => {
-                let (vlow, vhigh) = (self.stack_pop(bus), self.stack_pop(bus));
-                self.pc = hi_lo_to_u16(vhigh, vlow);
+            0xC9 => ret!(self, bus),
+            // RETI.
+            // Unlike EI, enables interrupts immediately.
+            0xD9 => {
+                ret!(self, bus);
+                self.ime_flag = true;
             },
 
             // RLCA.
             0x07 => {
-                self.flag.c_carry = self.acc >> 7 == 1;
-                self.acc = self.acc << 1 | self.acc >> 7;
-                self.flag.z_zero = self.acc == 0;
+                alu_r!(self, rlc_n, acc);
+                self.flag.z_zero = false;
+            },
+            // RLA.
+            0x17 => {
+                alu_r!(self, rl_n, acc);
+                self.flag.z_zero = false;
+            },
+            // RRCA.
+            0x0F => {
+                alu_r!(self, rrc_n, acc);
+                self.flag.z_zero = false;
+            },
+            // RRA.
+            0x1F => {
+                alu_r!(self, rr_n, acc);
+                self.flag.z_zero = false;
+            },
+
+            // RST 00H.
+            0xC7 => rst!(self, bus, 0x0000),
+            // RST 08H.
+            0xCF => rst!(self, bus, 0x0008),
+            // RST 10H.
+            0xD7 => rst!(self, bus, 0x0010),
+            // RST 18H.
+            0xDF => rst!(self, bus, 0x0018),
+            // RST 20H.
+            0xE7 => rst!(self, bus, 0x0020),
+            // RST 28H.
+            0xEF => rst!(self, bus, 0x0028),
+            // RST 30H.
+            0xF7 => rst!(self, bus, 0x0030),
+            // RST 38H.
+            0xFF => rst!(self, bus, 0x0038),
+
+
+            // SBC A,B.
+            0x98 => self.sub_n(self.b, self.flag.c_carry),
+            // SBC A,C.
+            0x99 => self.sub_n(self.c, self.flag.c_carry),
+            // SBC A,D.
+            0x9A => self.sub_n(self.d, self.flag.c_carry),
+            // SBC A,E.
+            0x9B => self.sub_n(self.e, self.flag.c_carry),
+            // SBC A,H.
+            0x9C => self.sub_n(self.h, self.flag.c_carry),
+            // SBC A,L.
+            0x9D => self.sub_n(self.l, self.flag.c_carry),
+            // SBC A,(HL).
+            0x9E => self.sub_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize), self.flag.c_carry),
+            // SBC A,A.
+            0x9F => self.sub_n(self.acc, self.flag.c_carry),
+            // SBC A,d8.
+            0xDE => {
+                let val = self.read_byte(bus);
+                self.sub_n(val, self.flag.c_carry);
+            },
+
+            // SCF.
+            0x37 => {
                 self.flag.n_substract = false;
                 self.flag.h_half_carry = false;
+                self.flag.c_carry = true;
             },
-            // RLA.
-            0x17 => rl!(self, acc),
 
             // STOP.
-            /* Halt until button pressed. Might be a better way to simulate. */
+            // Stops the clock until a button is pressed, or switches the speed on CGB if KEY1 armed it.
             0x10 => {
                 let _ = self.read_byte(bus);
-                panic!("STOP instruction called.")
+                // The divider is reset inside the SoC, not by a CPU bus access, so it takes no M-cycle.
+                bus.write_byte(REG_DIV as usize, 0);
+                if !bus.switch_speed() {
+                    log!(Target::Cpu, Level::Debug, "Entering STOP at PC {:#x}", self.pc);
+                    self.stopped = true;
+                }
+            },
+
+            // SUB B.
+            0x90 => self.sub_n(self.b, false),
+            // SUB C.
+            0x91 => self.sub_n(self.c, false),
+            // SUB D.
+            0x92 => self.sub_n(self.d, false),
+            // SUB E.
+            0x93 => self.sub_n(self.e, false),
+            // SUB H.
+            0x94 => self.sub_n(self.h, false),
+            // SUB L.
+            0x95 => self.sub_n(self.l, false),
+            // SUB (HL).
+            0x96 => self.sub_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize), false),
+            // SUB A.
+            0x97 => self.sub_n(self.acc, false),
+            // SUB d8.
+            0xD6 => {
+                let val = self.read_byte(bus);
+                self.sub_n(val, false);
             },
 
             // XOR B.
-            0xA8 => self.b ^= self.b,
+            0xA8 => self.xor_n(self.b),
             // XOR C.
-            0xA9 => self.c ^= self.c,
+            0xA9 => self.xor_n(self.c),
             // XOR D.
-            0xAA => self.d ^= self.d,
+            0xAA => self.xor_n(self.d),
             // XOR E.
-            0xAB => self.e ^= self.e,
+            0xAB => self.xor_n(self.e),
             // XOR H.
-            0xAC => self.h ^= self.h,
+            0xAC => self.xor_n(self.h),
             // XOR L.
-            0xAD => self.l ^= self.l,
+            0xAD => self.xor_n(self.l),
             // XOR A.
-            0xAF => self.acc ^= self.acc,
+            0xAF => self.xor_n(self.acc),
+            // XOR (HL).
+            0xAE => self.xor_n(self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
+            // XOR d8.
+            0xEE => {
+                let val = self.read_byte(bus);
+                self.xor_n(val);
+            },
 
-            _ => panic!("Unknown opcode {:#x} ({:#b}) at PC {:#x} ({})", opcode, opcode, self.pc - 1, self.pc - 1),
+            _ => {
+                log!(Target::Cpu, Level::Error, "Illegal opcode {:#x} at PC {:#x}, locking up", opcode, self.pc.wrapping_sub(1));
+                self.locked = true;
+                return Err(GameBoyError::IllegalOpcode {
+                    opcode,
+                    pc: self.pc.wrapping_sub(1),
+                    registers: self.registers(),
+                    ime: self.ime_flag,
+                    cycles: bus.cycles(),
+                });
+            },
         };
 
-        cycles += DURATION_MAINS[opcode as usize];
-        bus.register_cycles(cycles as u16);
+        // The clock was advanced by the accesses themselves, the tables only double check it.
+        // STOP also fetches its padding byte.
+        let elapsed = bus.cycles() - start_cycles;
+        let expected = match prefixed_opcode {
+            Some(op) => DURATION_PREFIXED[op as usize],
+            None => DURATION_MAINS[opcode as usize],
+        } as u64;
+        debug_assert!(opcode == 0x10 || elapsed == expected || elapsed == expected + DURATION_MAIN_EXTRA[opcode as usize] as u64,
+                      "Opcode {:#x} took {} cycles instead of {}", opcode, elapsed, expected);
+
+        if ei_delayed && self.ime_pending {
+            self.ime_pending = false;
+            self.ime_flag = true;
+        }
 
-        self.handle_timing();
+        Ok(())
     }
 
-    pub fn check_interrupt(&mut self, bus: &mut Bus) {
-        if !self.ime_flag {
+    pub fn check_interrupt<M: Memory>(&mut self, bus: &mut M) {
+        if self.locked || self.pending_interrupts(bus) == 0 {
             return;
         }
 
-        let int_byte = bus.read_byte(REG_IF as usize);
-
-        // Bit 0: V-Blank Interrupt Request (INT 40h) (1=Request)
-        if self.is_lcd_on(bus) {
-            interrupt!(self, bus, 0x0040, int_byte, 0, "Interrupt occured: V-Blank Interrupt Request");
+        // A pending interrupt ends HALT even with IME off.
+        self.halted = false;
+        if !self.ime_flag {
+            return;
+        }
+        self.ime_flag = false;
+
+        // Two wait states before the PC push.
+        self.internal_cycle(bus);
+        self.internal_cycle(bus);
+
+        // The source is picked after the high byte of PC is pushed. If that push overwrote IE (SP was 0x0000)
+        // and no enabled interrupt is left, the dispatch is cancelled and execution continues at 0x0000.
+        // A HALT bug left over from EI; HALT pushes the address of the HALT, so it runs again after the return.
+        let pc = if self.halt_bug { self.pc.wrapping_sub(1) } else { self.pc };
+        self.halt_bug = false;
+        let (pc_hi, pc_lo) = u16_to_hi_lo(pc);
+        self.stack_push(pc_hi, bus);
+        let pending = self.pending_interrupts(bus);
+        self.stack_push(pc_lo, bus);
+
+        if pending == 0 {
+            log!(Target::Interrupts, Level::Debug, "Interrupt dispatch cancelled by the IE push");
+            self.pc = 0x0000;
+        } else {
+            let bit = pending.trailing_zeros() as u8;
+            log!(Target::Interrupts, Level::Debug, "Interrupt occured: {} (INT {:02X}h)", INTERRUPT_NAMES[bit as usize], 0x40 + 8 * bit);
+
+            let if_byte = bus.read_byte(REG_IF as usize) & !(1 << bit);
+            bus.write_byte(REG_IF as usize, if_byte);
+            log!(Target::Interrupts, Level::Trace, "NEW IF {:#010b}", if_byte);
+
+            self.pc = 0x0040 + 8 * bit as u16;
         }
 
-        // Bit 1: LCD STAT Interrupt Request (INT 48h) (1=Request)
-        interrupt!(self, bus, 0x0048, int_byte, 1, "Interrupt occured: LCD STAT Interrupt Request");
+        self.internal_cycle(bus);
+    }
 
-        // Bit 2: Timer Interrupt Request (INT 50h) (1=Request)
-        interrupt!(self, bus, 0x0050, int_byte, 2, "Interrupt occured: Timer Interrupt Request");
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
 
-        // Bit 3: Serial Interrupt Request (INT 58h) (1=Request)
-        interrupt!(self, bus, 0x0058, int_byte, 3, "Interrupt occured: Serial Interrupt Request");
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
 
-        // Bit 4: Joypad Interrupt Request (INT 60h) (1=Request)
-        interrupt!(self, bus, 0x0060, int_byte, 4, "Interrupt occured: Joypad Interrupt Request");
+    // Returns true once per executed LD B,B.
+    pub fn take_breakpoint(&mut self) -> bool {
+        let breakpoint = self.breakpoint;
+        self.breakpoint = false;
+        breakpoint
     }
 
-    fn is_lcd_on(&self, bus: &Bus) -> bool {
-        let lcdc = bus.read_byte(REG_LCDC as usize);
-        lcdc >> 7 > 0
+    // Requested and enabled interrupts, IE & IF.
+    fn pending_interrupts<M: Memory>(&self, bus: &M) -> u8 {
+        bus.read_byte(REG_IE as usize) & bus.read_byte(REG_IF as usize) & 0x1F
     }
 
-    fn handle_timing(&self) {
-        // TODO
+    fn is_interrupt_pending<M: Memory>(&self, bus: &M) -> bool {
+        self.pending_interrupts(bus) != 0
     }
 
-    fn exec_prefixed_instruction(&mut self, opcode: u8)  {
+    fn exec_prefixed_instruction<M: Memory>(&mut self, opcode: u8, bus: &mut M)  {
         match opcode {
             // RLC B.
-            // 0x00 => { },
+            0x00 => alu_r!(self, rlc_n, b),
             // RLC C.
-            // 0x01 => { },
+            0x01 => alu_r!(self, rlc_n, c),
             // RLC D.
-            // 0x02 => { },
+            0x02 => alu_r!(self, rlc_n, d),
             // RLC E.
-            // 0x03 => { },
+            0x03 => alu_r!(self, rlc_n, e),
             // RLC H.
-            // 0x04 => { },
+            0x04 => alu_r!(self, rlc_n, h),
             // RLC L.
-            // 0x05 => { },
+            0x05 => alu_r!(self, rlc_n, l),
             // RLC (HL).
-            // 0x06 => { },
+            0x06 => alu_hl!(self, bus, rlc_n),
             // RLC A.
-            // 0x07 => { },
+            0x07 => alu_r!(self, rlc_n, acc),
             // RRC B.
-            // 0x08 => { },
+            0x08 => alu_r!(self, rrc_n, b),
             // RRC C.
-            // 0x09 => { },
+            0x09 => alu_r!(self, rrc_n, c),
             // RRC D.
-            // 0x0A => { },
+            0x0A => alu_r!(self, rrc_n, d),
             // RRC E.
-            // 0x0B => { },
+            0x0B => alu_r!(self, rrc_n, e),
             // RRC H.
-            // 0x0C => { },
+            0x0C => alu_r!(self, rrc_n, h),
             // RRC L.
-            // 0x0D => { },
+            0x0D => alu_r!(self, rrc_n, l),
             // RRC (HL).
-            // 0x0E => { },
+            0x0E => alu_hl!(self, bus, rrc_n),
             // RRC A.
-            // 0x0F => { },
+            0x0F => alu_r!(self, rrc_n, acc),
             // RL B.
-            // 0x10 => { },
+            0x10 => alu_r!(self, rl_n, b),
             // RL C.
-            0x11 => rl!(self, c),
+            0x11 => alu_r!(self, rl_n, c),
             // RL D.
-            // 0x12 => { },
+            0x12 => alu_r!(self, rl_n, d),
             // RL E.
-            // 0x13 => { },
+            0x13 => alu_r!(self, rl_n, e),
             // RL H.
-            // 0x14 => { },
+            0x14 => alu_r!(self, rl_n, h),
             // RL L.
-            // 0x15 => { },
+            0x15 => alu_r!(self, rl_n, l),
             // RL (HL).
-            // 0x16 => { },
+            0x16 => alu_hl!(self, bus, rl_n),
             // RL A.
-            // 0x17 => { },
+            0x17 => alu_r!(self, rl_n, acc),
             // RR B.
-            // 0x18 => { },
+            0x18 => alu_r!(self, rr_n, b),
             // RR C.
-            // 0x19 => { },
+            0x19 => alu_r!(self, rr_n, c),
             // RR D.
-            // 0x1A => { },
+            0x1A => alu_r!(self, rr_n, d),
             // RR E.
-            // 0x1B => { },
+            0x1B => alu_r!(self, rr_n, e),
             // RR H.
-            // 0x1C => { },
+            0x1C => alu_r!(self, rr_n, h),
             // RR L.
-            // 0x1D => { },
+            0x1D => alu_r!(self, rr_n, l),
             // RR (HL).
-            // 0x1E => { },
+            0x1E => alu_hl!(self, bus, rr_n),
             // RR A.
-            // 0x1F => { },
+            0x1F => alu_r!(self, rr_n, acc),
             // SLA B.
-            // 0x20 => { },
+            0x20 => alu_r!(self, sla_n, b),
             // SLA C.
-            // 0x21 => { },
+            0x21 => alu_r!(self, sla_n, c),
             // SLA D.
-            // 0x22 => { },
+            0x22 => alu_r!(self, sla_n, d),
             // SLA E.
-            // 0x23 => { },
+            0x23 => alu_r!(self, sla_n, e),
             // SLA H.
-            // 0x24 => { },
+            0x24 => alu_r!(self, sla_n, h),
             // SLA L.
-            // 0x25 => { },
+            0x25 => alu_r!(self, sla_n, l),
             // SLA (HL).
-            // 0x26 => { },
+            0x26 => alu_hl!(self, bus, sla_n),
             // SLA A.
-            // 0x27 => { },
+            0x27 => alu_r!(self, sla_n, acc),
             // SRA B.
-            // 0x28 => { },
+            0x28 => alu_r!(self, sra_n, b),
             // SRA C.
-            // 0x29 => { },
+            0x29 => alu_r!(self, sra_n, c),
             // SRA D.
-            // 0x2A => { },
+            0x2A => alu_r!(self, sra_n, d),
             // SRA E.
-            // 0x2B => { },
+            0x2B => alu_r!(self, sra_n, e),
             // SRA H.
-            // 0x2C => { },
+            0x2C => alu_r!(self, sra_n, h),
             // SRA L.
-            // 0x2D => { },
+            0x2D => alu_r!(self, sra_n, l),
             // SRA (HL).
-            // 0x2E => { },
+            0x2E => alu_hl!(self, bus, sra_n),
             // SRA A.
-            // 0x2F => { },
+            0x2F => alu_r!(self, sra_n, acc),
             // SWAP B.
-            // 0x30 => { },
+            0x30 => alu_r!(self, swap_n, b),
             // SWAP C.
-            // 0x31 => { },
+            0x31 => alu_r!(self, swap_n, c),
             // SWAP D.
-            // 0x32 => { },
+            0x32 => alu_r!(self, swap_n, d),
             // SWAP E.
-            // 0x33 => { },
+            0x33 => alu_r!(self, swap_n, e),
             // SWAP H.
-            // 0x34 => { },
+            0x34 => alu_r!(self, swap_n, h),
             // SWAP L.
-            // 0x35 => { },
+            0x35 => alu_r!(self, swap_n, l),
             // SWAP (HL).
-            // 0x36 => { },
+            0x36 => alu_hl!(self, bus, swap_n),
             // SWAP A.
-            // 0x37 => { },
+            0x37 => alu_r!(self, swap_n, acc),
             // SRL B.
-            // 0x38 => { },
+            0x38 => alu_r!(self, srl_n, b),
             // SRL C.
-            // 0x39 => { },
+            0x39 => alu_r!(self, srl_n, c),
             // SRL D.
-            // 0x3A => { },
+            0x3A => alu_r!(self, srl_n, d),
             // SRL E.
-            // 0x3B => { },
+            0x3B => alu_r!(self, srl_n, e),
             // SRL H.
-            // 0x3C => { },
+            0x3C => alu_r!(self, srl_n, h),
             // SRL L.
-            // 0x3D => { },
+            0x3D => alu_r!(self, srl_n, l),
             // SRL (HL).
-            // 0x3E => { },
+            0x3E => alu_hl!(self, bus, srl_n),
             // SRL A.
-            // 0x3F => { },
+            0x3F => alu_r!(self, srl_n, acc),
+
             // BIT 0,B.
-            // 0x40 => { },
+            0x40 => bit!(self, 0, self.b),
             // BIT 0,C.
-            // 0x41 => { },
+            0x41 => bit!(self, 0, self.c),
             // BIT 0,D.
-            // 0x42 => { },
+            0x42 => bit!(self, 0, self.d),
             // BIT 0,E.
-            // 0x43 => { },
+            0x43 => bit!(self, 0, self.e),
             // BIT 0,H.
-            // 0x44 => { },
+            0x44 => bit!(self, 0, self.h),
             // BIT 0,L.
-            // 0x45 => { },
+            0x45 => bit!(self, 0, self.l),
             // BIT 0,(HL).
-            // 0x46 => { },
+            0x46 => bit!(self, 0, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 0,A.
-            // 0x47 => { },
+            0x47 => bit!(self, 0, self.acc),
             // BIT 1,B.
-            // 0x48 => { },
+            0x48 => bit!(self, 1, self.b),
             // BIT 1,C.
-            // 0x49 => { },
+            0x49 => bit!(self, 1, self.c),
             // BIT 1,D.
-            // 0x4A => { },
+            0x4A => bit!(self, 1, self.d),
             // BIT 1,E.
-            // 0x4B => { },
+            0x4B => bit!(self, 1, self.e),
             // BIT 1,H.
-            // 0x4C => { },
+            0x4C => bit!(self, 1, self.h),
             // BIT 1,L.
-            // 0x4D => { },
+            0x4D => bit!(self, 1, self.l),
             // BIT 1,(HL).
-            // 0x4E => { },
+            0x4E => bit!(self, 1, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 1,A.
-            // 0x4F => { },
+            0x4F => bit!(self, 1, self.acc),
             // BIT 2,B.
-            // 0x50 => { },
+            0x50 => bit!(self, 2, self.b),
             // BIT 2,C.
-            // 0x51 => { },
+            0x51 => bit!(self, 2, self.c),
             // BIT 2,D.
-            // 0x52 => { },
+            0x52 => bit!(self, 2, self.d),
             // BIT 2,E.
-            // 0x53 => { },
+            0x53 => bit!(self, 2, self.e),
             // BIT 2,H.
-            // 0x54 => { },
+            0x54 => bit!(self, 2, self.h),
             // BIT 2,L.
-            // 0x55 => { },
+            0x55 => bit!(self, 2, self.l),
             // BIT 2,(HL).
-            // 0x56 => { },
+            0x56 => bit!(self, 2, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 2,A.
-            // 0x57 => { },
+            0x57 => bit!(self, 2, self.acc),
             // BIT 3,B.
-            // 0x58 => { },
+            0x58 => bit!(self, 3, self.b),
             // BIT 3,C.
-            // 0x59 => { },
+            0x59 => bit!(self, 3, self.c),
             // BIT 3,D.
-            // 0x5A => { },
+            0x5A => bit!(self, 3, self.d),
             // BIT 3,E.
-            // 0x5B => { },
+            0x5B => bit!(self, 3, self.e),
             // BIT 3,H.
-            // 0x5C => { },
+            0x5C => bit!(self, 3, self.h),
             // BIT 3,L.
-            // 0x5D => { },
+            0x5D => bit!(self, 3, self.l),
             // BIT 3,(HL).
-            // 0x5E => { },
+            0x5E => bit!(self, 3, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 3,A.
-            // 0x5F => { },
+            0x5F => bit!(self, 3, self.acc),
             // BIT 4,B.
-            // 0x60 => { },
+            0x60 => bit!(self, 4, self.b),
             // BIT 4,C.
-            // 0x61 => { },
+            0x61 => bit!(self, 4, self.c),
             // BIT 4,D.
-            // 0x62 => { },
+            0x62 => bit!(self, 4, self.d),
             // BIT 4,E.
-            // 0x63 => { },
+            0x63 => bit!(self, 4, self.e),
             // BIT 4,H.
-            // 0x64 => { },
+            0x64 => bit!(self, 4, self.h),
             // BIT 4,L.
-            // 0x65 => { },
+            0x65 => bit!(self, 4, self.l),
             // BIT 4,(HL).
-            // 0x66 => { },
+            0x66 => bit!(self, 4, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 4,A.
-            // 0x67 => { },
+            0x67 => bit!(self, 4, self.acc),
             // BIT 5,B.
-            // 0x68 => { },
+            0x68 => bit!(self, 5, self.b),
             // BIT 5,C.
-            // 0x69 => { },
+            0x69 => bit!(self, 5, self.c),
             // BIT 5,D.
-            // 0x6A => { },
+            0x6A => bit!(self, 5, self.d),
             // BIT 5,E.
-            // 0x6B => { },
+            0x6B => bit!(self, 5, self.e),
             // BIT 5,H.
-            // 0x6C => { },
+            0x6C => bit!(self, 5, self.h),
             // BIT 5,L.
-            // 0x6D => { },
+            0x6D => bit!(self, 5, self.l),
             // BIT 5,(HL).
-            // 0x6E => { },
+            0x6E => bit!(self, 5, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 5,A.
-            // 0x6F => { },
+            0x6F => bit!(self, 5, self.acc),
             // BIT 6,B.
-            // 0x70 => { },
+            0x70 => bit!(self, 6, self.b),
             // BIT 6,C.
-            // 0x71 => { },
+            0x71 => bit!(self, 6, self.c),
             // BIT 6,D.
-            // 0x72 => { },
+            0x72 => bit!(self, 6, self.d),
             // BIT 6,E.
-            // 0x73 => { },
+            0x73 => bit!(self, 6, self.e),
             // BIT 6,H.
-            // 0x74 => { },
+            0x74 => bit!(self, 6, self.h),
             // BIT 6,L.
-            // 0x75 => { },
+            0x75 => bit!(self, 6, self.l),
             // BIT 6,(HL).
-            // 0x76 => { },
+            0x76 => bit!(self, 6, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 6,A.
-            // 0x77 => { },
+            0x77 => bit!(self, 6, self.acc),
             // BIT 7,B.
-            // 0x78 => { },
+            0x78 => bit!(self, 7, self.b),
             // BIT 7,C.
-            // 0x79 => { },
+            0x79 => bit!(self, 7, self.c),
             // BIT 7,D.
-            // 0x7A => { },
+            0x7A => bit!(self, 7, self.d),
             // BIT 7,E.
-            // 0x7B => { },
+            0x7B => bit!(self, 7, self.e),
             // BIT 7,H.
-            0x7C => {
-                self.flag.z_zero = self.h >> 7 == 0;
-                self.flag.n_substract = false;
-                self.flag.h_half_carry = true;
-            },
+            0x7C => bit!(self, 7, self.h),
             // BIT 7,L.
-            // 0x7D => { },
+            0x7D => bit!(self, 7, self.l),
             // BIT 7,(HL).
-            // 0x7E => { },
+            0x7E => bit!(self, 7, self.read_mem(bus, hi_lo_to_u16(self.h, self.l) as usize)),
             // BIT 7,A.
-            // 0x7F => { },
+            0x7F => bit!(self, 7, self.acc),
+
             // RES 0,B.
-            // 0x80 => { },
+            0x80 => alu_r!(self, res_b_n, b, 0),
             // RES 0,C.
-            // 0x81 => { },
+            0x81 => alu_r!(self, res_b_n, c, 0),
             // RES 0,D.
-            // 0x82 => { },
+            0x82 => alu_r!(self, res_b_n, d, 0),
             // RES 0,E.
-            // 0x83 => { },
+            0x83 => alu_r!(self, res_b_n, e, 0),
             // RES 0,H.
-            // 0x84 => { },
+            0x84 => alu_r!(self, res_b_n, h, 0),
             // RES 0,L.
-            // 0x85 => { },
+            0x85 => alu_r!(self, res_b_n, l, 0),
             // RES 0,(HL).
-            // 0x86 => { },
+            0x86 => alu_hl!(self, bus, res_b_n, 0),
             // RES 0,A.
-            // 0x87 => { },
+            0x87 => alu_r!(self, res_b_n, acc, 0),
             // RES 1,B.
-            // 0x88 => { },
+            0x88 => alu_r!(self, res_b_n, b, 1),
             // RES 1,C.
-            // 0x89 => { },
+            0x89 => alu_r!(self, res_b_n, c, 1),
             // RES 1,D.
-            // 0x8A => { },
+            0x8A => alu_r!(self, res_b_n, d, 1),
             // RES 1,E.
-            // 0x8B => { },
+            0x8B => alu_r!(self, res_b_n, e, 1),
             // RES 1,H.
-            // 0x8C => { },
+            0x8C => alu_r!(self, res_b_n, h, 1),
             // RES 1,L.
-            // 0x8D => { },
+            0x8D => alu_r!(self, res_b_n, l, 1),
             // RES 1,(HL).
-            // 0x8E => { },
+            0x8E => alu_hl!(self, bus, res_b_n, 1),
             // RES 1,A.
-            // 0x8F => { },
+            0x8F => alu_r!(self, res_b_n, acc, 1),
             // RES 2,B.
-            // 0x90 => { },
+            0x90 => alu_r!(self, res_b_n, b, 2),
             // RES 2,C.
-            // 0x91 => { },
+            0x91 => alu_r!(self, res_b_n, c, 2),
             // RES 2,D.
-            // 0x92 => { },
+            0x92 => alu_r!(self, res_b_n, d, 2),
             // RES 2,E.
-            // 0x93 => { },
+            0x93 => alu_r!(self, res_b_n, e, 2),
             // RES 2,H.
-            // 0x94 => { },
+            0x94 => alu_r!(self, res_b_n, h, 2),
             // RES 2,L.
-            // 0x95 => { },
+            0x95 => alu_r!(self, res_b_n, l, 2),
             // RES 2,(HL).
-            // 0x96 => { },
+            0x96 => alu_hl!(self, bus, res_b_n, 2),
             // RES 2,A.
-            // 0x97 => { },
+            0x97 => alu_r!(self, res_b_n, acc, 2),
             // RES 3,B.
-            // 0x98 => { },
+            0x98 => alu_r!(self, res_b_n, b, 3),
             // RES 3,C.
-            // 0x99 => { },
+            0x99 => alu_r!(self, res_b_n, c, 3),
             // RES 3,D.
-            // 0x9A => { },
+            0x9A => alu_r!(self, res_b_n, d, 3),
             // RES 3,E.
-            // 0x9B => { },
+            0x9B => alu_r!(self, res_b_n, e, 3),
             // RES 3,H.
-            // 0x9C => { },
+            0x9C => alu_r!(self, res_b_n, h, 3),
             // RES 3,L.
-            // 0x9D => { },
+            0x9D => alu_r!(self, res_b_n, l, 3),
             // RES 3,(HL).
-            // 0x9E => { },
+            0x9E => alu_hl!(self, bus, res_b_n, 3),
             // RES 3,A.
-            // 0x9F => { },
+            0x9F => alu_r!(self, res_b_n, acc, 3),
             // RES 4,B.
-            // 0xA0 => { },
+            0xA0 => alu_r!(self, res_b_n, b, 4),
             // RES 4,C.
-            // 0xA1 => { },
+            0xA1 => alu_r!(self, res_b_n, c, 4),
             // RES 4,D.
-            // 0xA2 => { },
+            0xA2 => alu_r!(self, res_b_n, d, 4),
             // RES 4,E.
-            // 0xA3 => { },
+            0xA3 => alu_r!(self, res_b_n, e, 4),
             // RES 4,H.
-            // 0xA4 => { },
+            0xA4 => alu_r!(self, res_b_n, h, 4),
             // RES 4,L.
-            // 0xA5 => { },
+            0xA5 => alu_r!(self, res_b_n, l, 4),
             // RES 4,(HL).
-            // 0xA6 => { },
+            0xA6 => alu_hl!(self, bus, res_b_n, 4),
             // RES 4,A.
-            // 0xA7 => { },
+            0xA7 => alu_r!(self, res_b_n, acc, 4),
             // RES 5,B.
-            // 0xA8 => { },
+            0xA8 => alu_r!(self, res_b_n, b, 5),
             // RES 5,C.
-            // 0xA9 => { },
+            0xA9 => alu_r!(self, res_b_n, c, 5),
             // RES 5,D.
-            // 0xAA => { },
+            0xAA => alu_r!(self, res_b_n, d, 5),
             // RES 5,E.
-            // 0xAB => { },
+            0xAB => alu_r!(self, res_b_n, e, 5),
             // RES 5,H.
-            // 0xAC => { },
+            0xAC => alu_r!(self, res_b_n, h, 5),
             // RES 5,L.
-            // 0xAD => { },
+            0xAD => alu_r!(self, res_b_n, l, 5),
             // RES 5,(HL).
-            // 0xAE => { },
+            0xAE => alu_hl!(self, bus, res_b_n, 5),
             // RES 5,A.
-            // 0xAF => { },
+            0xAF => alu_r!(self, res_b_n, acc, 5),
             // RES 6,B.
-            // 0xB0 => { },
+            0xB0 => alu_r!(self, res_b_n, b, 6),
             // RES 6,C.
-            // 0xB1 => { },
+            0xB1 => alu_r!(self, res_b_n, c, 6),
             // RES 6,D.
-            // 0xB2 => { },
+            0xB2 => alu_r!(self, res_b_n, d, 6),
             // RES 6,E.
-            // 0xB3 => { },
+            0xB3 => alu_r!(self, res_b_n, e, 6),
             // RES 6,H.
-            // 0xB4 => { },
+            0xB4 => alu_r!(self, res_b_n, h, 6),
             // RES 6,L.
-            // 0xB5 => { },
+            0xB5 => alu_r!(self, res_b_n, l, 6),
             // RES 6,(HL).
-            // 0xB6 => { },
+            0xB6 => alu_hl!(self, bus, res_b_n, 6),
             // RES 6,A.
-            // 0xB7 => { },
+            0xB7 => alu_r!(self, res_b_n, acc, 6),
             // RES 7,B.
-            // 0xB8 => { },
+            0xB8 => alu_r!(self, res_b_n, b, 7),
             // RES 7,C.
-            // 0xB9 => { },
+            0xB9 => alu_r!(self, res_b_n, c, 7),
             // RES 7,D.
-            // 0xBA => { },
+            0xBA => alu_r!(self, res_b_n, d, 7),
             // RES 7,E.
-            // 0xBB => { },
+            0xBB => alu_r!(self, res_b_n, e, 7),
             // RES 7,H.
-            // 0xBC => { },
+            0xBC => alu_r!(self, res_b_n, h, 7),
             // RES 7,L.
-            // 0xBD => { },
+            0xBD => alu_r!(self, res_b_n, l, 7),
             // RES 7,(HL).
-            // 0xBE => { },
+            0xBE => alu_hl!(self, bus, res_b_n, 7),
             // RES 7,A.
-            // 0xBF => { },
+            0xBF => alu_r!(self, res_b_n, acc, 7),
+
             // SET 0,B.
-            // 0xC0 => { },
+            0xC0 => alu_r!(self, set_b_n, b, 0),
             // SET 0,C.
-            // 0xC1 => { },
+            0xC1 => alu_r!(self, set_b_n, c, 0),
             // SET 0,D.
-            // 0xC2 => { },
+            0xC2 => alu_r!(self, set_b_n, d, 0),
             // SET 0,E.
-            // 0xC3 => { },
+            0xC3 => alu_r!(self, set_b_n, e, 0),
             // SET 0,H.
-            // 0xC4 => { },
+            0xC4 => alu_r!(self, set_b_n, h, 0),
             // SET 0,L.
-            // 0xC5 => { },
+            0xC5 => alu_r!(self, set_b_n, l, 0),
             // SET 0,(HL).
-            // 0xC6 => { },
+            0xC6 => alu_hl!(self, bus, set_b_n, 0),
             // SET 0,A.
-            // 0xC7 => { },
+            0xC7 => alu_r!(self, set_b_n, acc, 0),
             // SET 1,B.
-            // 0xC8 => { },
+            0xC8 => alu_r!(self, set_b_n, b, 1),
             // SET 1,C.
-            // 0xC9 => { },
+            0xC9 => alu_r!(self, set_b_n, c, 1),
             // SET 1,D.
-            // 0xCA => { },
+            0xCA => alu_r!(self, set_b_n, d, 1),
             // SET 1,E.
-            // 0xCB => { },
+            0xCB => alu_r!(self, set_b_n, e, 1),
             // SET 1,H.
-            // 0xCC => { },
+            0xCC => alu_r!(self, set_b_n, h, 1),
             // SET 1,L.
-            // 0xCD => { },
+            0xCD => alu_r!(self, set_b_n, l, 1),
             // SET 1,(HL).
-            // 0xCE => { },
+            0xCE => alu_hl!(self, bus, set_b_n, 1),
             // SET 1,A.
-            // 0xCF => { },
+            0xCF => alu_r!(self, set_b_n, acc, 1),
             // SET 2,B.
-            // 0xD0 => { },
+            0xD0 => alu_r!(self, set_b_n, b, 2),
             // SET 2,C.
-            // 0xD1 => { },
+            0xD1 => alu_r!(self, set_b_n, c, 2),
             // SET 2,D.
-            // 0xD2 => { },
+            0xD2 => alu_r!(self, set_b_n, d, 2),
             // SET 2,E.
-            // 0xD3 => { },
+            0xD3 => alu_r!(self, set_b_n, e, 2),
             // SET 2,H.
-            // 0xD4 => { },
+            0xD4 => alu_r!(self, set_b_n, h, 2),
             // SET 2,L.
-            // 0xD5 => { },
+            0xD5 => alu_r!(self, set_b_n, l, 2),
             // SET 2,(HL).
-            // 0xD6 => { },
+            0xD6 => alu_hl!(self, bus, set_b_n, 2),
             // SET 2,A.
-            // 0xD7 => { },
+            0xD7 => alu_r!(self, set_b_n, acc, 2),
             // SET 3,B.
-            // 0xD8 => { },
+            0xD8 => alu_r!(self, set_b_n, b, 3),
             // SET 3,C.
-            // 0xD9 => { },
+            0xD9 => alu_r!(self, set_b_n, c, 3),
             // SET 3,D.
-            // 0xDA => { },
+            0xDA => alu_r!(self, set_b_n, d, 3),
             // SET 3,E.
-            // 0xDB => { },
+            0xDB => alu_r!(self, set_b_n, e, 3),
             // SET 3,H.
-            // 0xDC => { },
+            0xDC => alu_r!(self, set_b_n, h, 3),
             // SET 3,L.
-            // 0xDD => { },
+            0xDD => alu_r!(self, set_b_n, l, 3),
             // SET 3,(HL).
-            // 0xDE => { },
+            0xDE => alu_hl!(self, bus, set_b_n, 3),
             // SET 3,A.
-            // 0xDF => { },
+            0xDF => alu_r!(self, set_b_n, acc, 3),
             // SET 4,B.
-            // 0xE0 => { },
+            0xE0 => alu_r!(self, set_b_n, b, 4),
             // SET 4,C.
-            // 0xE1 => { },
+            0xE1 => alu_r!(self, set_b_n, c, 4),
             // SET 4,D.
-            // 0xE2 => { },
+            0xE2 => alu_r!(self, set_b_n, d, 4),
             // SET 4,E.
-            // 0xE3 => { },
+            0xE3 => alu_r!(self, set_b_n, e, 4),
             // SET 4,H.
-            // 0xE4 => { },
+            0xE4 => alu_r!(self, set_b_n, h, 4),
             // SET 4,L.
-            // 0xE5 => { },
+            0xE5 => alu_r!(self, set_b_n, l, 4),
             // SET 4,(HL).
-            // 0xE6 => { },
+            0xE6 => alu_hl!(self, bus, set_b_n, 4),
             // SET 4,A.
-            // 0xE7 => { },
+            0xE7 => alu_r!(self, set_b_n, acc, 4),
             // SET 5,B.
-            // 0xE8 => { },
+            0xE8 => alu_r!(self, set_b_n, b, 5),
             // SET 5,C.
-            // 0xE9 => { },
+            0xE9 => alu_r!(self, set_b_n, c, 5),
             // SET 5,D.
-            // 0xEA => { },
+            0xEA => alu_r!(self, set_b_n, d, 5),
             // SET 5,E.
-            // 0xEB => { },
+            0xEB => alu_r!(self, set_b_n, e, 5),
             // SET 5,H.
-            // 0xEC => { },
+            0xEC => alu_r!(self, set_b_n, h, 5),
             // SET 5,L.
-            // 0xED => { },
+            0xED => alu_r!(self, set_b_n, l, 5),
             // SET 5,(HL).
-            // 0xEE => { },
+            0xEE => alu_hl!(self, bus, set_b_n, 5),
             // SET 5,A.
-            // 0xEF => { },
+            0xEF => alu_r!(self, set_b_n, acc, 5),
             // SET 6,B.
-            // 0xF0 => { },
+            0xF0 => alu_r!(self, set_b_n, b, 6),
             // SET 6,C.
-            // 0xF1 => { },
+            0xF1 => alu_r!(self, set_b_n, c, 6),
             // SET 6,D.
-            // 0xF2 => { },
+            0xF2 => alu_r!(self, set_b_n, d, 6),
             // SET 6,E.
-            // 0xF3 => { },
+            0xF3 => alu_r!(self, set_b_n, e, 6),
             // SET 6,H.
-            // 0xF4 => { },
+            0xF4 => alu_r!(self, set_b_n, h, 6),
             // SET 6,L.
-            // 0xF5 => { },
+            0xF5 => alu_r!(self, set_b_n, l, 6),
             // SET 6,(HL).
-            // 0xF6 => { },
+            0xF6 => alu_hl!(self, bus, set_b_n, 6),
             // SET 6,A.
-            // 0xF7 => { },
+            0xF7 => alu_r!(self, set_b_n, acc, 6),
             // SET 7,B.
-            // 0xF8 => { },
+            0xF8 => alu_r!(self, set_b_n, b, 7),
             // SET 7,C.
-            // 0xF9 => { },
+            0xF9 => alu_r!(self, set_b_n, c, 7),
             // SET 7,D.
-            // 0xFA => { },
+            0xFA => alu_r!(self, set_b_n, d, 7),
             // SET 7,E.
-            // 0xFB => { },
+            0xFB => alu_r!(self, set_b_n, e, 7),
             // SET 7,H.
-            // 0xFC => { },
+            0xFC => alu_r!(self, set_b_n, h, 7),
             // SET 7,L.
-            // 0xFD => { },
+            0xFD => alu_r!(self, set_b_n, l, 7),
             // SET 7,(HL).
-            // 0xFE => { },
+            0xFE => alu_hl!(self, bus, set_b_n, 7),
             // SET 7,A.
-            // 0xFF => { },
-
-            _ => panic!("Unknown perfixed opcode {:#x} ({:#b})", opcode, opcode),
+            0xFF => alu_r!(self, set_b_n, acc, 7),
         };
     }
 
-    fn read_opcode(&mut self, bus: &Bus) -> u8 {
-        self.read_byte(bus)
+    fn add_n(&mut self, val: u8, carry: bool) {
+        let carry = carry as u8;
+        let res = self.acc.wrapping_add(val).wrapping_add(carry);
+        self.flag.z_zero = res == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = (self.acc & 0xF) + (val & 0xF) + carry > 0xF;
+        self.flag.c_carry = self.acc as u16 + val as u16 + carry as u16 > 0xFF;
+        self.acc = res;
     }
 
-    fn read_byte(&mut self, bus: &Bus) -> u8 {
-        let addr = self.pc as usize;
+    fn sub_n(&mut self, val: u8, carry: bool) {
+        let carry = carry as u8;
+        let res = self.acc.wrapping_sub(val).wrapping_sub(carry);
+        self.flag.z_zero = res == 0;
+        self.flag.n_substract = true;
+        self.flag.h_half_carry = (self.acc & 0xF) < (val & 0xF) + carry;
+        self.flag.c_carry = (self.acc as u16) < val as u16 + carry as u16;
+        self.acc = res;
+    }
+
+    // INC and DEC leave the carry flag alone.
+    fn inc_n(&mut self, val: u8) -> u8 {
+        let res = val.wrapping_add(1);
+        self.flag.z_zero = res == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = val & 0xF == 0xF;
+        res
+    }
+
+    fn dec_n(&mut self, val: u8) -> u8 {
+        let res = val.wrapping_sub(1);
+        self.flag.z_zero = res == 0;
+        self.flag.n_substract = true;
+        self.flag.h_half_carry = val & 0xF == 0;
+        res
+    }
 
-        self.pc += 1;
-        // PC is virtually mapped to the ROM bank memory section.
-        if self.pc > ROM_BANK_ADDR_END {
-            println!("PC overflow to {:#x}", self.pc);
-            self.pc -= ROM_BANK_ADDR_END - ROM_BANK_ADDR_START + 1;
+    fn and_n(&mut self, val: u8) {
+        self.acc &= val;
+        self.flag.z_zero = self.acc == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = true;
+        self.flag.c_carry = false;
+    }
+
+    fn or_n(&mut self, val: u8) {
+        self.acc |= val;
+        self.flag.z_zero = self.acc == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = false;
+        self.flag.c_carry = false;
+    }
+
+    fn xor_n(&mut self, val: u8) {
+        self.acc ^= val;
+        self.flag.z_zero = self.acc == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = false;
+        self.flag.c_carry = false;
+    }
+
+    fn add_hl(&mut self, val: u16) {
+        let hl = hi_lo_to_u16(self.h, self.l);
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = (hl & 0x0FFF) + (val & 0x0FFF) > 0x0FFF;
+        self.flag.c_carry = hl as u32 + val as u32 > 0xFFFF;
+        let (hi, lo) = u16_to_hi_lo(hl.wrapping_add(val));
+        self.h = hi;
+        self.l = lo;
+    }
+
+    // SP + signed 8 bit offset, flags are computed on the unsigned low byte addition.
+    fn sp_plus_r8(&mut self, offs: u8) -> u16 {
+        self.flag.z_zero = false;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = (self.sp & 0xF) + (offs as u16 & 0xF) > 0xF;
+        self.flag.c_carry = (self.sp & 0xFF) + offs as u16 > 0xFF;
+        self.sp.wrapping_add(offs as i8 as u16)
+    }
+
+    // Decimal adjust A after a BCD addition or substraction.
+    fn daa(&mut self) {
+        let mut adjust = 0u8;
+        let mut carry = self.flag.c_carry;
+
+        if self.flag.n_substract {
+            if self.flag.c_carry { adjust |= 0x60; }
+            if self.flag.h_half_carry { adjust |= 0x06; }
+            self.acc = self.acc.wrapping_sub(adjust);
+        } else {
+            if self.flag.c_carry || self.acc > 0x99 {
+                adjust |= 0x60;
+                carry = true;
+            }
+            if self.flag.h_half_carry || self.acc & 0xF > 0x9 { adjust |= 0x06; }
+            self.acc = self.acc.wrapping_add(adjust);
         }
 
+        self.flag.z_zero = self.acc == 0;
+        self.flag.h_half_carry = false;
+        self.flag.c_carry = carry;
+    }
+
+    fn set_rotate_flags(&mut self, res: u8, carry: bool) {
+        self.flag.z_zero = res == 0;
+        self.flag.n_substract = false;
+        self.flag.h_half_carry = false;
+        self.flag.c_carry = carry;
+    }
+
+    fn rlc_n(&mut self, val: u8) -> u8 {
+        let res = val.rotate_left(1);
+        self.set_rotate_flags(res, val >> 7 == 1);
+        res
+    }
+
+    fn rrc_n(&mut self, val: u8) -> u8 {
+        let res = val.rotate_right(1);
+        self.set_rotate_flags(res, val & 1 == 1);
+        res
+    }
+
+    fn rl_n(&mut self, val: u8) -> u8 {
+        let res = val << 1 | self.flag.c_carry as u8;
+        self.set_rotate_flags(res, val >> 7 == 1);
+        res
+    }
+
+    fn rr_n(&mut self, val: u8) -> u8 {
+        let res = val >> 1 | (self.flag.c_carry as u8) << 7;
+        self.set_rotate_flags(res, val & 1 == 1);
+        res
+    }
+
+    fn sla_n(&mut self, val: u8) -> u8 {
+        let res = val << 1;
+        self.set_rotate_flags(res, val >> 7 == 1);
+        res
+    }
+
+    // Arithmetic shift, bit 7 keeps its value.
+    fn sra_n(&mut self, val: u8) -> u8 {
+        let res = val >> 1 | val & 0x80;
+        self.set_rotate_flags(res, val & 1 == 1);
+        res
+    }
+
+    fn swap_n(&mut self, val: u8) -> u8 {
+        let res = val.rotate_left(4);
+        self.set_rotate_flags(res, false);
+        res
+    }
+
+    fn srl_n(&mut self, val: u8) -> u8 {
+        let res = val >> 1;
+        self.set_rotate_flags(res, val & 1 == 1);
+        res
+    }
+
+    fn res_b_n(&mut self, val: u8, bit: u8) -> u8 {
+        val & !(1 << bit)
+    }
+
+    fn set_b_n(&mut self, val: u8, bit: u8) -> u8 {
+        val | 1 << bit
+    }
+
+    // Every memory access takes one M-cycle, the rest of the hardware is clocked before the access happens.
+    fn read_mem<M: Memory>(&self, bus: &mut M, addr: usize) -> u8 {
+        bus.register_cycles(4);
         bus.read_byte(addr)
     }
 
-    fn read_low_high(&mut self, bus: &Bus) -> (u8, u8) {
-        (self.read_byte(bus), self.read_byte(bus))
+    fn write_mem<M: Memory>(&self, bus: &mut M, addr: usize, byte: u8) {
+        bus.register_cycles(4);
+        bus.write_byte(addr, byte);
     }
 
-    fn stack_push(&mut self, byte: u8, bus: &mut Bus) {
-        // println!("Write to STACK[{:#x}] == {:#x}", self.sp, byte);
-        bus.write_byte(self.sp as usize, byte);
-        self.sp -= 1;
+    // An M-cycle without memory access, e.g. the 16-bit ALU or taking a branch.
+    fn internal_cycle<M: Memory>(&self, bus: &mut M) {
+        bus.register_cycles(4);
+    }
 
-        if self.sp < STACK_BOTTOM {
-            panic!("Stack pointer reached bottom: {:#x}.", self.sp);
+    fn read_opcode<M: Memory>(&mut self, bus: &mut M) -> u8 {
+        // The HALT bug fails to increment PC once.
+        if self.halt_bug {
+            self.halt_bug = false;
+            return self.read_mem(bus, self.pc as usize);
         }
+        self.read_byte(bus)
+    }
+
+    fn read_byte<M: Memory>(&mut self, bus: &mut M) -> u8 {
+        let addr = self.pc as usize;
+        self.pc = self.pc.wrapping_add(1);
+        self.read_mem(bus, addr)
     }
 
-    fn stack_push_d16(&mut self, dbyte: u16, bus: &mut Bus) {
+    fn read_low_high<M: Memory>(&mut self, bus: &mut M) -> (u8, u8) {
+        (self.read_byte(bus), self.read_byte(bus))
+    }
+
+    // The stack can live anywhere in memory (games usually move it to WRAM), so SP is not bounds checked.
+    fn stack_push<M: Memory>(&mut self, byte: u8, bus: &mut M) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_mem(bus, self.sp as usize, byte);
+    }
+
+    fn stack_push_d16<M: Memory>(&mut self, dbyte: u16, bus: &mut M) {
         let (addr_hi, addr_lo) = u16_to_hi_lo(dbyte);
         self.stack_push(addr_hi, bus);
         self.stack_push(addr_lo, bus);
     }
 
-    fn stack_pop(&mut self, bus: &Bus) -> u8 {
-        self.sp += 1;
-        if self.sp > STACK_TOP {
-            panic!("Stack pointer reached top: {:#x}", self.sp);
+    fn stack_pop<M: Memory>(&mut self, bus: &mut M) -> u8 {
+        // TODO too much "as usize", try to apply the From or Into trait
+        let byte = self.read_mem(bus, self.sp as usize);
+        self.sp = self.sp.wrapping_add(1);
+        byte
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::Bus;
+    use cartridge::{Cartridge, ROM_BANK_SIZE};
+    use joypad::Buttons;
+    use model::Model;
+    use timer::Timer;
+
+    // ROM only cartridge with the program at 0x0000.
+    fn bus_with(program: &[u8], ie: u8, if_reg: u8) -> Bus {
+        let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+        rom[..program.len()].copy_from_slice(program);
+        let mut bus = Bus::new(Timer::new(), Cartridge::new(rom).unwrap(), Model::Dmg);
+        bus.write_byte(REG_IE as usize, ie);
+        bus.write_byte(REG_IF as usize, if_reg);
+        bus
+    }
+
+    fn cpu_at(pc: u16, sp: u16) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_registers(Registers { sp, pc, ..Registers::default() });
+        cpu
+    }
+
+    fn step(cpu: &mut CPU, bus: &mut Bus) {
+        cpu.next_instruction(bus).unwrap();
+        cpu.check_interrupt(bus);
+    }
+
+    #[test]
+    fn halt_bug_executes_the_next_byte_twice() {
+        // HALT; INC A with IME off and VBLANK pending.
+        let mut bus = bus_with(&[0x76, 0x3C], 0x01, 0x01);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+
+        for _ in 0..3 {
+            step(&mut cpu, &mut bus);
         }
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.acc, 2);
+        assert_eq!(cpu.pc, 0x0002);
+    }
 
-        // println!("Read from STACK[{:#x}]", self.sp);
-        // TODO too much "as usize", try to apply the From or Into trait
-        bus.read_byte(self.sp as usize)
+    #[test]
+    fn halt_bug_during_a_dispatch_returns_to_the_halt() {
+        // EI; HALT with the timer interrupt pending, INC A at its handler.
+        let mut program = vec![0; 0x51];
+        program[..2].copy_from_slice(&[0xFB, 0x76]);
+        program[0x50] = 0x3C;
+        let mut bus = bus_with(&program, 0x04, 0x04);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+
+        step(&mut cpu, &mut bus);
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!((bus.read_byte(0xFFFD), bus.read_byte(0xFFFC)), (0x00, 0x01));
+
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.acc, 1);
+        assert_eq!(cpu.pc, 0x0051);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn halt_waits_for_a_pending_interrupt() {
+        let mut bus = bus_with(&[0x76, 0x3C], 0x01, 0x00);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+
+        step(&mut cpu, &mut bus);
+        step(&mut cpu, &mut bus);
+        assert!(cpu.is_halted());
+
+        bus.write_byte(REG_IF as usize, 0x01);
+        step(&mut cpu, &mut bus);
+        step(&mut cpu, &mut bus);
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.acc, 1);
+    }
+
+    #[test]
+    fn stop_waits_for_a_selected_button() {
+        // STOP; INC A with only the directions selected.
+        let mut bus = bus_with(&[0x10, 0x00, 0x3C], 0x00, 0x00);
+        bus.write_byte(REG_P1 as usize, 0x20);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+
+        step(&mut cpu, &mut bus);
+        assert!(cpu.is_stopped());
+
+        bus.set_buttons(Buttons { a: true, ..Buttons::default() });
+        step(&mut cpu, &mut bus);
+        assert!(cpu.is_stopped());
+
+        bus.set_buttons(Buttons { right: true, ..Buttons::default() });
+        step(&mut cpu, &mut bus);
+        assert!(!cpu.is_stopped());
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.acc, 1);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP with the timer interrupt pending.
+        let mut bus = bus_with(&[0xFB, 0x00, 0x00], 0x04, 0x04);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+
+        step(&mut cpu, &mut bus);
+        assert!(!cpu.ime_flag);
+        assert_eq!(cpu.pc, 0x0001);
+
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(bus.read_byte(REG_IF as usize) & 0x1F, 0x00);
+        // The pushed return address is the second NOP.
+        assert_eq!((bus.read_byte(0xFFFD), bus.read_byte(0xFFFC)), (0x00, 0x02));
+    }
+
+    #[test]
+    fn lowest_pending_bit_is_served_first() {
+        let mut bus = bus_with(&[0x00], 0x1F, 0b0_0110);
+        let mut cpu = cpu_at(0x0000, 0xFFFE);
+        cpu.ime_flag = true;
+
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0048);
+        assert_eq!(bus.read_byte(REG_IF as usize) & 0x1F, 0b0_0100);
+    }
+
+    #[test]
+    fn ie_overwritten_by_the_pc_push_cancels_the_dispatch() {
+        // SP = 0x0000 makes the high byte of PC (0x12) land in IE, which disables VBLANK.
+        let mut bus = bus_with(&[], 0x01, 0x01);
+        let mut cpu = cpu_at(0x1234, 0x0000);
+        cpu.ime_flag = true;
+
+        step(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(bus.read_byte(REG_IE as usize), 0x12);
+        assert_eq!(bus.read_byte(REG_IF as usize) & 0x1F, 0x01);
+        assert!(!cpu.ime_flag);
+    }
+
+    #[test]
+    fn reset_powers_on_with_ime_off() {
+        let mut cpu = cpu_at(0x1234, 0xFFFE);
+        cpu.ime_flag = true;
+
+        cpu.reset();
+        assert!(!cpu.ime_flag);
+        assert_eq!(cpu.pc, 0x0000);
+    }
+}
@@ -0,0 +1,47 @@
+use constants::*;
+
+// Bytes copied to OAM by one transfer, one per M-cycle.
+pub const DMA_LENGTH: u16 = 0xA0;
+
+// OAM DMA (0xFF46), copies XX00 - XX9F to 0xFE00 - 0xFE9F.
+#[derive(Debug)]
+pub struct Dma {
+    source: u16,
+    // Bytes copied so far, the transfer is done at DMA_LENGTH.
+    copied: u16,
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        Dma::new()
+    }
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma { source: 0, copied: DMA_LENGTH }
+    }
+
+    // Starting a new transfer restarts from the beginning even while one is running.
+    pub fn start(&mut self, byte: u8) {
+        // 0xE0 - 0xFF go to the echo of the internal RAM.
+        let page = if byte >= 0xE0 { byte - 0x20 } else { byte };
+        self.source = (page as u16) << 8;
+        self.copied = 0;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.copied < DMA_LENGTH
+    }
+
+    // Advances one M-cycle, returns the source address and the OAM address of the byte to copy.
+    pub fn tick(&mut self) -> Option<(usize, usize)> {
+        if !self.is_active() {
+            return None;
+        }
+
+        let offset = self.copied;
+        self.copied += 1;
+        Some(((self.source + offset) as usize, MEM_MAP_OAM_START + offset as usize))
+    }
+}
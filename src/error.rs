@@ -0,0 +1,34 @@
+use cpu::Registers;
+use std::error::Error;
+use std::fmt;
+
+// Emulation faults the program running on the machine can cause.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameBoyError {
+    // One of the 11 unused opcodes (0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD) was
+    // fetched. The CPU locks up like the hardware does until the next reset.
+    IllegalOpcode {
+        opcode: u8,
+        // Address of the opcode.
+        pc: u16,
+        // Machine state right after the fetch.
+        registers: Registers,
+        ime: bool,
+        cycles: u64,
+    },
+}
+
+impl fmt::Display for GameBoyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GameBoyError::IllegalOpcode { opcode, pc, ref registers, ime, cycles } => write!(f,
+                "illegal opcode {:#04x} at PC {:#06x} after {} cycles, CPU locked up \
+                 (A={:02X} F={:02X} B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X} SP={:04X} IME={})",
+                opcode, pc, cycles,
+                registers.a, registers.f, registers.b, registers.c, registers.d, registers.e, registers.h, registers.l,
+                registers.sp, ime as u8),
+        }
+    }
+}
+
+impl Error for GameBoyError { }
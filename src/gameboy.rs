@@ -1,55 +1,146 @@
-use cpu::CPU;
+use cpu::{CPU, Registers};
 use cpu;
 use bus::Bus;
 use timer::Timer;
-use io::IO;
-use io;
-use std::rc::Rc;
-use std::cell::RefCell;
+use cartridge::Cartridge;
+use error::GameBoyError;
+use model::Model;
+use joypad::Buttons;
+use trace::{Target, Level};
 use std::fmt;
 use constants::*;
 
+// Where a bounded run ends, with both limits whichever is reached first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Frames(u64),
+    // T-cycles since power on.
+    Cycles(u64),
+    Both { frames: u64, cycles: u64 },
+}
+
 pub struct GameBoy {
     cpu: CPU,
-    boot_rom: Vec<u8>,
-    ram: Rc<RefCell<[u8; RAM_SIZE]>>,
-    io: IO,
+    model: Model,
+    // Without a boot ROM the machine starts at 0x0100 in the post-boot state of the model.
+    boot_rom: Option<Vec<u8>>,
     bus: Bus,
 }
 
 impl GameBoy {
-    pub fn new(boot_rom: Vec<u8>) -> GameBoy {
-        let ram = Rc::new(RefCell::new([0; RAM_SIZE]));
+    pub fn new(boot_rom: Option<Vec<u8>>, cartridge: Cartridge, model: Model) -> GameBoy {
         let timer = Timer::default();
-        let io = IO;
 
         GameBoy {
-            boot_rom: boot_rom,
+            model,
+            boot_rom,
             cpu: CPU::new(),
-            ram: ram.clone(),
-            io: io,
-            bus: Bus::new(ram.clone(), timer),
+            bus: Bus::new(timer, cartridge, model),
+        }
+    }
+
+    // Runs until the PPU completes a frame, or until the CPU enters STOP and waits for the buttons.
+    pub fn run_frame(&mut self) -> Result<(), GameBoyError> {
+        while !self.bus.ppu.take_frame_ready() {
+            self.step()?;
+            if self.cpu.is_stopped() {
+                break;
+            }
         }
+        Ok(())
     }
 
-    pub fn turn_on(&mut self) {
-        self.cpu.reset();
-        self.copy_rom_to_memory();
-        self.io.init(&mut self.bus.timer);
+    // Runs until the limit is reached. Returns early in STOP as nothing can press a button.
+    pub fn run_for(&mut self, limit: Limit) -> Result<(), GameBoyError> {
+        let (frames, cycles) = match limit {
+            Limit::Frames(frames) => (Some(frames), None),
+            Limit::Cycles(cycles) => (None, Some(cycles)),
+            Limit::Both { frames, cycles } => (Some(frames), Some(cycles)),
+        };
 
-        loop {
-            self.cpu.next_instruction(&mut self.bus);
-            self.cpu.check_interrupt(&mut self.bus);
-            self.io.operate(&mut self.bus);
-            println!("{:#?}", self);
+        let mut completed = 0;
+        while frames.is_none_or(|frames| completed < frames)
+            && cycles.is_none_or(|cycles| self.cycles() < cycles)
+            && !self.cpu.is_stopped() {
+            self.step()?;
+            if self.bus.ppu.take_frame_ready() {
+                completed += 1;
+            }
         }
+        Ok(())
+    }
+
+    // Executes one instruction with its interrupt handling. A fault is reported once, the locked up machine
+    // can keep stepping afterwards.
+    pub fn step(&mut self) -> Result<(), GameBoyError> {
+        self.cpu.next_instruction(&mut self.bus)?;
+        self.cpu.check_interrupt(&mut self.bus);
+        log!(Target::Cpu, Level::Trace, "{:#?}", self);
+        Ok(())
+    }
+
+    // Replaces the pressed state of every button, call it between frames.
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.bus.set_buttons(buttons);
+    }
+
+    // Read-only snapshot of the CPU registers.
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    // Returns true once per software breakpoint (LD B,B) hit.
+    pub fn take_breakpoint(&mut self) -> bool {
+        self.cpu.take_breakpoint()
+    }
+
+    // Bytes sent over the serial port so far.
+    pub fn serial_output(&self) -> &[u8] {
+        self.bus.serial.output()
     }
 
-    // TODO make sure its copied to the right place (maybe keep separate?)
-    // TODO think about moving this operation to the bus so GameBoy does not need a mutable refcell of mem.
-    fn copy_rom_to_memory(&mut self) {
-        for idx in 0..self.boot_rom.len() {
-            self.ram.borrow_mut()[idx] = self.boot_rom[idx];
+    // True after an illegal opcode until the next reset.
+    pub fn is_locked(&self) -> bool {
+        self.cpu.is_locked()
+    }
+
+    // True while the CPU waits in STOP for a button press.
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.is_stopped()
+    }
+
+    // Cartridge RAM to write to a save file, None for cartridges without a battery.
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.bus.cartridge.battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        self.bus.cartridge.load_battery_ram(data)
+    }
+
+    // T-cycles elapsed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.bus.cycles()
+    }
+
+    // Last completed frame, one shade (0 = white - 3 = black) per pixel, row major.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.bus.ppu.framebuffer()
+    }
+
+    // Puts the machine in its power on state, or the post-boot state without a boot ROM.
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        match self.boot_rom {
+            Some(ref boot_rom) => {
+                self.cpu.reset();
+                self.bus.map_boot_rom(boot_rom.clone());
+            },
+            None => {
+                self.cpu.reset_post_boot(self.model.post_boot_registers());
+                self.bus.load_post_boot_io(&self.model.post_boot_io());
+                self.bus.timer.set_divider(self.model.post_boot_divider());
+            },
         }
     }
 }
@@ -78,3 +169,54 @@ LY:   {:#010b}",
         ly)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_boot_state_has_no_dma_running() {
+        let cartridge = Cartridge::new(vec![0; 0x8000]).unwrap();
+        let mut gameboy = GameBoy::new(None, cartridge, Model::Dmg);
+        gameboy.reset();
+
+        assert!(!gameboy.bus.dma.is_active());
+        assert_eq!(gameboy.bus.read_byte(REG_DMA as usize), 0xFF);
+        assert_eq!(gameboy.bus.read_byte(0x0100), 0x00);
+    }
+
+    #[test]
+    fn reset_leaves_halt_and_clears_the_machine() {
+        // HALT with IE = 0 never wakes up.
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        let mut gameboy = GameBoy::new(None, Cartridge::new(rom).unwrap(), Model::Dmg);
+        gameboy.reset();
+        gameboy.bus.write_byte(0xC000, 0x12);
+        for _ in 0..10 {
+            gameboy.step().unwrap();
+        }
+        assert!(gameboy.cpu.is_halted());
+
+        gameboy.reset();
+        assert!(!gameboy.cpu.is_halted());
+        assert_eq!(gameboy.cycles(), 0);
+        assert_eq!(gameboy.bus.read_byte(0xC000), 0x00);
+        gameboy.step().unwrap();
+        assert_eq!(gameboy.registers().pc, 0x0101);
+    }
+
+    #[test]
+    fn reset_unlocks_the_cpu() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0xD3;
+        let mut gameboy = GameBoy::new(None, Cartridge::new(rom).unwrap(), Model::Dmg);
+        gameboy.reset();
+        assert!(gameboy.step().is_err());
+        assert!(gameboy.is_locked());
+
+        gameboy.reset();
+        assert!(!gameboy.is_locked());
+        assert_eq!(gameboy.registers().pc, 0x0100);
+    }
+}
@@ -0,0 +1,132 @@
+// P1 bits 4 and 5, a line is selected when its bit is 0.
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_ACTIONS: u8 = 1 << 5;
+
+// Host side state of the buttons, true means pressed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl Buttons {
+    // Active high nibble in P1 bit order: Right/A, Left/B, Up/Select, Down/Start.
+    fn directions(&self) -> u8 {
+        (self.right as u8) | (self.left as u8) << 1 | (self.up as u8) << 2 | (self.down as u8) << 3
+    }
+
+    fn actions(&self) -> u8 {
+        (self.a as u8) | (self.b as u8) << 1 | (self.select as u8) << 2 | (self.start as u8) << 3
+    }
+}
+
+// P1/JOYP register (0xFF00).
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8,
+    buttons: Buttons,
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            buttons: Buttons::default(),
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.select | self.input_lines()
+    }
+
+    // Returns true if the joypad interrupt is requested.
+    pub fn write(&mut self, byte: u8) -> bool {
+        let old_lines = self.input_lines();
+        self.select = byte & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        falling_edge(old_lines, self.input_lines())
+    }
+
+    // Returns true if the joypad interrupt is requested.
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool {
+        let old_lines = self.input_lines();
+        self.buttons = buttons;
+        falling_edge(old_lines, self.input_lines())
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    // True if a pressed button pulls one of the selected lines low, this ends STOP.
+    pub fn is_any_line_low(&self) -> bool {
+        self.input_lines() != 0x0F
+    }
+
+    // Active low P10 - P13, both groups are ANDed together when both are selected.
+    fn input_lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.buttons.directions();
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= self.buttons.actions();
+        }
+        !pressed & 0x0F
+    }
+}
+
+// The interrupt fires when any of P10 - P13 goes from high to low.
+fn falling_edge(old_lines: u8, new_lines: u8) -> bool {
+    old_lines & !new_lines != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIGHT_AND_B: Buttons = Buttons {
+        right: true, left: false, up: false, down: false,
+        a: false, b: true, select: false, start: false,
+    };
+
+    #[test]
+    fn select_bits_pick_the_button_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(RIGHT_AND_B);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read() & 0x0F, 0b1111);
+        joypad.write(0x20);
+        assert_eq!(joypad.read() & 0x0F, 0b1110);
+        joypad.write(0x10);
+        assert_eq!(joypad.read() & 0x0F, 0b1101);
+        joypad.write(0x00);
+        assert_eq!(joypad.read() & 0x0F, 0b1100);
+    }
+
+    #[test]
+    fn interrupt_only_for_selected_lines_going_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+
+        assert!(!joypad.set_buttons(Buttons { a: true, ..Buttons::default() }));
+        assert!(joypad.set_buttons(Buttons { right: true, ..Buttons::default() }));
+        // Releasing is a rising edge.
+        assert!(!joypad.set_buttons(Buttons::default()));
+        // Selecting a group with a button held pulls its line low.
+        joypad.set_buttons(Buttons { a: true, ..Buttons::default() });
+        assert!(joypad.write(0x10));
+    }
+}
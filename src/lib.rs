@@ -0,0 +1,19 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+pub mod trace;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod dma;
+pub mod error;
+pub mod gameboy;
+pub mod joypad;
+pub mod mbc;
+pub mod model;
+pub mod ppu;
+pub mod serial;
+pub mod timer;
+pub mod constants;
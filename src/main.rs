@@ -1,38 +1,256 @@
-#![allow(dead_code)]
-#![allow(unused_imports)]
-
-mod bus;
-mod cpu;
-mod gameboy;
-mod timer;
-mod io;
-mod constants;
+extern crate gameboy_emu;
+extern crate png;
 
 use std::env::{args};
-use std::fs::{File};
-use std::io::{Read};
-use std::default;
-use std::rc::Rc;
-use std::cell::RefCell;
-use bus::{Bus};
-use cpu::{CPU};
-use gameboy::{GameBoy};
+use std::fs::{self, File};
+use std::io::{ErrorKind, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use gameboy_emu::{constants, trace};
+use gameboy_emu::cartridge::{Cartridge};
+use gameboy_emu::constants::{SCREEN_WIDTH, SCREEN_HEIGHT, SHADE_GRAYSCALE, CYCLES_PER_FRAME, CYCLES_PER_SECOND};
+use gameboy_emu::gameboy::{GameBoy, Limit};
+use gameboy_emu::model::{Model};
+
+const USAGE: &str = "Usage: gameboy_emu [OPTIONS] <CARTRIDGE_FILE>
+Options:
+  --boot-rom <FILE>            Run the boot ROM first. Without it the machine starts in the post-boot state of the model.
+  --model <dmg|mgb|cgb|sgb>    Hardware model, dmg by default.
+  --trace <TARGET[=LEVEL],..>  Trace targets: cpu, bus, io, timer, interrupts, all. Levels: off, error, warn, info, debug, trace.
+  --headless                   Run as fast as possible and print the serial output on exit.
+  --frames <N>                 Stop after N frames, implies --headless.
+  --cycles <N>                 Stop after N T-cycles, implies --headless.
+  --screenshot <PNG_FILE>      Save the last frame of a headless run.
+  --save-dir <DIR>             Load and store the battery backed cartridge RAM as <DIR>/<CARTRIDGE>.sav.
+  --speed <FACTOR>             Emulation speed relative to the hardware, 0.01 - 100, 1 by default.
+  --help                       Print this message.";
+
+// Exit codes.
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_USAGE_ERROR: i32 = 2;
+
+// Range of --speed.
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 100.0;
+
+// Battery RAM is written out every 10 seconds of emulated time, and on exit.
+const SAVE_INTERVAL_FRAMES: u64 = 600;
+
+struct Options {
+    cartridge: String,
+    boot_rom: Option<String>,
+    model: Model,
+    headless: bool,
+    // Ends the run, None runs until STOP.
+    limit: Option<Limit>,
+    screenshot: Option<String>,
+    save_dir: Option<PathBuf>,
+    speed: f64,
+}
 
 fn main() {
-    GameBoy
-        ::new(fetch_dmg_boot_rom())
-        .turn_on();
+    let options = match parse_args(args().skip(1).collect()) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(msg) => {
+            eprintln!("error: {}\nRun with --help for the usage.", msg);
+            process::exit(EXIT_USAGE_ERROR);
+        },
+    };
+
+    if let Err(msg) = run(options) {
+        eprintln!("error: {}", msg);
+        process::exit(EXIT_RUNTIME_ERROR);
+    }
 }
 
-fn fetch_dmg_boot_rom() -> Vec<u8> {
-    if args().count() < 2 {
-        panic!("Missing argument(s). Call: ./binary <DMG_ROM_FILE>.");
+// Returns None when the help is requested.
+fn parse_args(args: Vec<String>) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        cartridge: String::new(),
+        boot_rom: None,
+        model: Model::Dmg,
+        headless: false,
+        limit: None,
+        screenshot: None,
+        save_dir: None,
+        speed: 1.0,
+    };
+    let mut cartridge = None;
+    let mut frames = None;
+    let mut cycles = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--headless" => options.headless = true,
+            "--boot-rom" | "--model" | "--trace" | "--frames" | "--cycles" | "--screenshot" | "--save-dir" | "--speed" => {
+                let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+                match arg.as_str() {
+                    "--boot-rom" => options.boot_rom = Some(value),
+                    "--model" => options.model = Model::from_name(&value)
+                        .ok_or_else(|| format!("unknown model {}", value))?,
+                    "--trace" => trace::configure(&value)?,
+                    "--frames" => frames = Some(parse_number(&arg, &value)?),
+                    "--cycles" => cycles = Some(parse_number(&arg, &value)?),
+                    "--screenshot" => options.screenshot = Some(value),
+                    "--save-dir" => options.save_dir = Some(PathBuf::from(value)),
+                    _ => options.speed = match value.parse::<f64>() {
+                        Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => speed,
+                        _ => return Err(format!("invalid speed {}, expected a number from {} to {}", value, MIN_SPEED, MAX_SPEED)),
+                    },
+                }
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if cartridge.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => cartridge = Some(arg),
+        }
+    }
+
+    options.cartridge = cartridge.ok_or("missing cartridge file")?;
+    options.limit = match (frames, cycles) {
+        (Some(frames), Some(cycles)) => Some(Limit::Both { frames, cycles }),
+        (Some(frames), None) => Some(Limit::Frames(frames)),
+        (None, Some(cycles)) => Some(Limit::Cycles(cycles)),
+        (None, None) => None,
+    };
+    if options.limit.is_some() {
+        options.headless = true;
     }
+    if options.screenshot.is_some() && options.limit.is_none() {
+        return Err("--screenshot needs --frames or --cycles".to_string());
+    }
+
+    Ok(Some(options))
+}
+
+fn parse_number(option: &str, value: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("invalid number {} for {}", value, option))
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let boot_rom = match options.boot_rom {
+        Some(ref path) => {
+            let boot_rom = read_file(path)?;
+            if boot_rom.len() != constants::BOOT_ROM_SIZE {
+                return Err(format!("boot ROM {} must be {} bytes, got {}", path, constants::BOOT_ROM_SIZE, boot_rom.len()));
+            }
+            Some(boot_rom)
+        },
+        None => None,
+    };
 
-    let dmg_rom_name = args().nth(1).unwrap();
-    let mut rom_file = File::open(dmg_rom_name).unwrap();
-    let mut rom: Vec<u8> = Vec::new();
-    let _ = rom_file.read_to_end(&mut rom);
+    let cartridge = Cartridge::new(read_file(&options.cartridge)?)
+        .map_err(|msg| format!("invalid cartridge {}: {}", options.cartridge, msg))?;
+    let mut gameboy = GameBoy::new(boot_rom, cartridge, options.model);
+
+    let save_path = match options.save_dir {
+        Some(ref dir) => {
+            if !dir.is_dir() {
+                let problem = if dir.exists() { "is not a directory" } else { "does not exist" };
+                return Err(format!("save directory {} {}", dir.display(), problem));
+            }
+            let path = save_path(dir, &options.cartridge);
+            load_save(&mut gameboy, &path)?;
+            // Writing the save right away reports an unwritable directory now rather than at the first autosave.
+            write_save(&gameboy, &path)?;
+            Some(path)
+        },
+        None => None,
+    };
+
+    gameboy.reset();
+    // The output and the save are still useful when the program faults, the fault is reported last.
+    let result = emulate(&mut gameboy, &options, save_path.as_ref());
+
+    if options.headless {
+        print!("{}", String::from_utf8_lossy(gameboy.serial_output()));
+    }
+    if let Some(ref path) = save_path {
+        write_save(&gameboy, path)?;
+    }
+    if let Some(ref path) = options.screenshot {
+        write_screenshot(path, gameboy.framebuffer())
+            .map_err(|msg| format!("cannot write screenshot {}: {}", path, msg))?;
+    }
+
+    result
+}
+
+fn emulate(gameboy: &mut GameBoy, options: &Options, save_path: Option<&PathBuf>) -> Result<(), String> {
+    if let Some(limit) = options.limit {
+        return gameboy.run_for(limit).map_err(|err| err.to_string());
+    }
+
+    let frame_time = Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CYCLES_PER_SECOND as f64 / options.speed);
+    let mut frames = 0;
+
+    // Nothing can press a button yet, so STOP ends the run.
+    while !gameboy.is_stopped() {
+        let started = Instant::now();
+        gameboy.run_frame().map_err(|err| err.to_string())?;
+        frames += 1;
+
+        if frames % SAVE_INTERVAL_FRAMES == 0 {
+            if let Some(path) = save_path {
+                write_save(gameboy, path)?;
+            }
+        }
+        if !options.headless {
+            if let Some(remaining) = frame_time.checked_sub(started.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// <save dir>/<cartridge file name without extension>.sav
+fn save_path(dir: &Path, cartridge: &str) -> PathBuf {
+    let stem = Path::new(cartridge).file_stem().unwrap_or_default();
+    dir.join(stem).with_extension("sav")
+}
+
+// A missing save file is a fresh game, not an error.
+fn load_save(gameboy: &mut GameBoy, path: &Path) -> Result<(), String> {
+    if gameboy.battery_ram().is_none() {
+        return Ok(());
+    }
+
+    match fs::read(path) {
+        Ok(data) => gameboy.load_battery_ram(&data)
+            .map_err(|msg| format!("cannot load save {}: {}", path.display(), msg)),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("cannot read save {}: {}", path.display(), err)),
+    }
+}
+
+fn write_save(gameboy: &GameBoy, path: &Path) -> Result<(), String> {
+    match gameboy.battery_ram() {
+        Some(ram) => fs::write(path, ram).map_err(|err| format!("cannot write save {}: {}", path.display(), err)),
+        None => Ok(()),
+    }
+}
+
+// Saves the framebuffer as an 8 bit grayscale PNG.
+fn write_screenshot(path: &str, framebuffer: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let pixels: Vec<u8> = framebuffer.iter().map(|&shade| SHADE_GRAYSCALE[shade as usize]).collect();
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer.write_image_data(&pixels).map_err(|err| err.to_string())
+}
 
-    rom
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("cannot read {}: {}", path, err))
 }
@@ -0,0 +1,578 @@
+use cartridge::{ROM_BANK_SIZE, RAM_BANK_SIZE};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const EXTERNAL_RAM_START: usize = 0xA000;
+
+// Memory bank controller of a cartridge.
+// ROM addresses are 0x0000 - 0x7FFF, RAM addresses are 0xA000 - 0xBFFF.
+pub trait Mbc {
+    fn read_rom(&self, addr: usize) -> u8;
+    fn write_rom(&mut self, addr: usize, byte: u8);
+    fn read_ram(&self, addr: usize) -> u8;
+    fn write_ram(&mut self, addr: usize, byte: u8);
+    // Whole external RAM, what a battery keeps between sessions.
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    // Power cycle, the bank registers go back to their initial values. RAM and the RTC are battery backed.
+    fn reset(&mut self);
+}
+
+fn rom_bank_count(rom: &[u8]) -> usize {
+    (rom.len() / ROM_BANK_SIZE).max(1)
+}
+
+fn ram_bank_count(ram: &[u8]) -> usize {
+    (ram.len() / RAM_BANK_SIZE).max(1)
+}
+
+// Reads from a ROM bank, bank numbers beyond the ROM size wrap around like the unconnected address lines do.
+fn read_rom_bank(rom: &[u8], bank: usize, addr: usize) -> u8 {
+    let bank = bank % rom_bank_count(rom);
+    let offset = bank * ROM_BANK_SIZE + (addr & (ROM_BANK_SIZE - 1));
+    rom.get(offset).cloned().unwrap_or(0xFF)
+}
+
+fn ram_offset(ram: &[u8], bank: usize, addr: usize) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+
+    let bank = bank % ram_bank_count(ram);
+    Some((bank * RAM_BANK_SIZE + (addr - EXTERNAL_RAM_START)) % ram.len())
+}
+
+// 32 KiB ROM with optional 8 KiB RAM and no banking.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly { rom, ram: vec![0; ram_size] }
+    }
+}
+
+impl Mbc for RomOnly {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn reset(&mut self) { }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        self.rom.get(addr).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, _addr: usize, _byte: u8) { }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        match ram_offset(&self.ram, 0, addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, byte: u8) {
+        if let Some(offset) = ram_offset(&self.ram, 0, addr) {
+            self.ram[offset] = byte;
+        }
+    }
+}
+
+// Up to 2 MiB ROM and 32 KiB RAM.
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // 5 bit ROM bank register (0x2000 - 0x3FFF).
+    rom_bank: usize,
+    // 2 bit secondary register (0x4000 - 0x5FFF), upper ROM bank bits or RAM bank.
+    bank2: usize,
+    // Banking mode (0x6000 - 0x7FFF), 1 applies bank2 to the 0x0000 and RAM areas too.
+    advanced_mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            bank2: 0,
+            advanced_mode: false,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode { self.bank2 } else { 0 }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.bank2 = 0;
+        self.advanced_mode = false;
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        let bank = if addr < ROM_BANK_SIZE {
+            if self.advanced_mode { self.bank2 << 5 } else { 0 }
+        } else {
+            self.bank2 << 5 | self.rom_bank
+        };
+        read_rom_bank(&self.rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: usize, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                let bank = (byte & 0x1F) as usize;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            },
+            0x4000..=0x5FFF => self.bank2 = (byte & 0x03) as usize,
+            _ => self.advanced_mode = byte & 1 == 1,
+        }
+    }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match ram_offset(&self.ram, self.ram_bank(), addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, byte: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        if let Some(offset) = ram_offset(&self.ram, self.ram_bank(), addr) {
+            self.ram[offset] = byte;
+        }
+    }
+}
+
+const MBC2_RAM_SIZE: usize = 0x200;
+
+// Up to 256 KiB ROM with 512 x 4 bits of built-in RAM.
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: usize,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: vec![0; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        let bank = if addr < ROM_BANK_SIZE { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: usize, byte: u8) {
+        if addr >= ROM_BANK_SIZE {
+            return;
+        }
+
+        // Address bit 8 selects between the RAM enable and the ROM bank register.
+        if addr & 0x0100 == 0 {
+            self.ram_enabled = byte & 0x0F == 0x0A;
+        } else {
+            let bank = (byte & 0x0F) as usize;
+            self.rom_bank = if bank == 0 { 1 } else { bank };
+        }
+    }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        // Only the lower nibble is wired, the upper one reads as 1s. The 512 bytes are mirrored.
+        self.ram[(addr - EXTERNAL_RAM_START) % MBC2_RAM_SIZE] | 0xF0
+    }
+
+    fn write_ram(&mut self, addr: usize, byte: u8) {
+        if self.ram_enabled {
+            self.ram[(addr - EXTERNAL_RAM_START) % MBC2_RAM_SIZE] = byte & 0x0F;
+        }
+    }
+}
+
+const RTC_SECONDS: usize = 0;
+const RTC_MINUTES: usize = 1;
+const RTC_HOURS: usize = 2;
+const RTC_DAY_LOW: usize = 3;
+const RTC_DAY_HIGH: usize = 4;
+
+// Real time clock of MBC3 carts. Registers 0x08 - 0x0C: seconds, minutes, hours, day low, day high.
+// Day high holds day bit 8 (bit 0), halt (bit 6) and day counter carry (bit 7).
+struct Rtc {
+    regs: [u8; 5],
+    latched: [u8; 5],
+    last_update: u64,
+    latch_armed: bool,
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Rtc {
+    fn new() -> Rtc {
+        Rtc {
+            regs: [0; 5],
+            latched: [0; 5],
+            last_update: unix_seconds(),
+            latch_armed: false,
+        }
+    }
+
+    fn is_halted(&self) -> bool {
+        self.regs[RTC_DAY_HIGH] >> 6 & 1 == 1
+    }
+
+    // Advances the counters by the wall clock time passed since the last update.
+    fn update(&mut self) {
+        let now = unix_seconds();
+        let elapsed = now.saturating_sub(self.last_update);
+        self.last_update = now;
+
+        if self.is_halted() || elapsed == 0 {
+            return;
+        }
+
+        let days = (self.regs[RTC_DAY_HIGH] as u64 & 1) << 8 | self.regs[RTC_DAY_LOW] as u64;
+        let total = self.regs[RTC_SECONDS] as u64 +
+            self.regs[RTC_MINUTES] as u64 * 60 +
+            self.regs[RTC_HOURS] as u64 * 3600 +
+            days * 86400 +
+            elapsed;
+
+        let days = total / 86400;
+        self.regs[RTC_SECONDS] = (total % 60) as u8;
+        self.regs[RTC_MINUTES] = (total / 60 % 60) as u8;
+        self.regs[RTC_HOURS] = (total / 3600 % 24) as u8;
+        self.regs[RTC_DAY_LOW] = days as u8;
+
+        let mut day_high = self.regs[RTC_DAY_HIGH] & 0b1100_0000 | (days >> 8 & 1) as u8;
+        if days > 0x1FF {
+            day_high |= 0b1000_0000;
+        }
+        self.regs[RTC_DAY_HIGH] = day_high;
+    }
+
+    // Writing 0 then 1 to 0x6000 - 0x7FFF copies the live counters into the readable registers.
+    fn write_latch(&mut self, byte: u8) {
+        if self.latch_armed && byte == 1 {
+            self.update();
+            self.latched = self.regs;
+        }
+        self.latch_armed = byte == 0;
+    }
+
+    fn read(&self, reg: usize) -> u8 {
+        self.latched[reg]
+    }
+
+    fn write(&mut self, reg: usize, byte: u8) {
+        self.update();
+        self.regs[reg] = match reg {
+            RTC_SECONDS | RTC_MINUTES => byte & 0x3F,
+            RTC_HOURS => byte & 0x1F,
+            RTC_DAY_HIGH => byte & 0b1100_0001,
+            _ => byte,
+        };
+    }
+}
+
+// Up to 2 MiB ROM, 32 KiB RAM and an optional real time clock.
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    rom_bank: usize,
+    // 0x00 - 0x03 selects a RAM bank, 0x08 - 0x0C an RTC register.
+    ram_bank: usize,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_timer: bool) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc: if has_timer { Some(Rtc::new()) } else { None },
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        let bank = if addr < ROM_BANK_SIZE { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: usize, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                let bank = (byte & 0x7F) as usize;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            },
+            0x4000..=0x5FFF => self.ram_bank = byte as usize,
+            _ => if let Some(rtc) = self.rtc.as_mut() {
+                rtc.write_latch(byte);
+            },
+        }
+    }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match (self.ram_bank, &self.rtc) {
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank - 0x08),
+            (0x00..=0x03, _) => match ram_offset(&self.ram, self.ram_bank, addr) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, byte: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        match (self.ram_bank, &mut self.rtc) {
+            (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank - 0x08, byte),
+            (0x00..=0x03, _) => if let Some(offset) = ram_offset(&self.ram, self.ram_bank, addr) {
+                self.ram[offset] = byte;
+            },
+            _ => { },
+        }
+    }
+}
+
+// Up to 8 MiB ROM and 128 KiB RAM.
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // 9 bit ROM bank, bank 0 is selectable in the 0x4000 - 0x7FFF area.
+    rom_bank: usize,
+    ram_bank: usize,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+    }
+
+    fn read_rom(&self, addr: usize) -> u8 {
+        let bank = if addr < ROM_BANK_SIZE { 0 } else { self.rom_bank };
+        read_rom_bank(&self.rom, bank, addr)
+    }
+
+    fn write_rom(&mut self, addr: usize, byte: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = byte & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = self.rom_bank & 0x100 | byte as usize,
+            0x3000..=0x3FFF => self.rom_bank = self.rom_bank & 0xFF | ((byte & 1) as usize) << 8,
+            0x4000..=0x5FFF => self.ram_bank = (byte & 0x0F) as usize,
+            _ => { },
+        }
+    }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match ram_offset(&self.ram, self.ram_bank, addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, byte: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        if let Some(offset) = ram_offset(&self.ram, self.ram_bank, addr) {
+            self.ram[offset] = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every bank starts with its number, low byte first.
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    fn bank_at(mbc: &dyn Mbc, addr: usize) -> usize {
+        mbc.read_rom(addr) as usize | (mbc.read_rom(addr + 1) as usize) << 8
+    }
+
+    #[test]
+    fn mbc1_bank_switching() {
+        let mut mbc = Mbc1::new(rom(64), 0);
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, 0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(bank_at(&mbc, 0x4000), 5);
+
+        // The secondary register supplies bits 5-6, and only in mode 1 the 0x0000 area too.
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(bank_at(&mbc, 0x4000), 0x25);
+        assert_eq!(bank_at(&mbc, 0x0000), 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(bank_at(&mbc, 0x0000), 0x20);
+
+        // Bank 0x20 can't be selected in the 0x4000 area, it becomes 0x21.
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, 0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc1_ram_needs_enabling() {
+        let mut mbc = Mbc1::new(rom(4), 0x2000);
+
+        mbc.write_ram(0xA000, 0x12);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x12);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rom_and_ram_banks() {
+        let mut mbc = Mbc3::new(rom(128), 0x8000, false);
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, 0x4000), 1);
+        mbc.write_rom(0x2000, 0x45);
+        assert_eq!(bank_at(&mbc, 0x4000), 0x45);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0xA000, 0x22);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x22);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_rom_bank() {
+        let mut mbc = Mbc5::new(rom(512), 0);
+
+        // Unlike the other MBCs bank 0 can be mapped at 0x4000.
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, 0x4000), 0);
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at(&mbc, 0x4000), 0x102);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(bank_at(&mbc, 0x4000), 0x02);
+    }
+}
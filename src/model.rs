@@ -0,0 +1,77 @@
+use cpu::Registers;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    // Original Game Boy.
+    Dmg,
+    // Game Boy Pocket.
+    Mgb,
+    // Game Boy Color.
+    Cgb,
+    // Super Game Boy, runs the DMG hardware inside the SNES adapter.
+    Sgb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "cgb" => Some(Model::Cgb),
+            "sgb" => Some(Model::Sgb),
+            _ => None,
+        }
+    }
+
+    // CPU state the boot ROM leaves behind when it jumps to 0x0100.
+    pub fn post_boot_registers(&self) -> Registers {
+        let (a, f, b, c, d, e, h, l) = match *self {
+            Model::Dmg => (0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+        };
+
+        Registers { a, f, b, c, d, e, h, l, sp: 0xFFFE, pc: 0x0100 }
+    }
+
+    // Internal 16 bit divider of the timer, DIV reads its upper byte.
+    pub fn post_boot_divider(&self) -> u16 {
+        match *self {
+            Model::Dmg | Model::Mgb | Model::Sgb => 0xABCC,
+            Model::Cgb => 0x0000,
+        }
+    }
+
+    // IO register values (address, value) the boot ROM leaves behind.
+    pub fn post_boot_io(&self) -> Vec<(u16, u8)> {
+        let sc = match *self {
+            Model::Dmg | Model::Mgb | Model::Sgb => 0x7E,
+            Model::Cgb => 0x7F,
+        };
+
+        vec![
+            // P1, SB, SC.
+            (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF02, sc),
+            // TIMA, TMA, TAC. DIV is set through the internal divider.
+            (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+            // IF.
+            (0xFF0F, 0xE1),
+            // Sound channels 1 - 4 (NR10 - NR44).
+            (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+            (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+            (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+            // Sound control (NR50 - NR52).
+            (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+            // LCDC, STAT, SCY, SCX, LY, LYC, DMA, BGP, OBP0, OBP1, WY, WX.
+            (0xFF40, 0x91), (0xFF41, 0x85), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF44, 0x00),
+            (0xFF45, 0x00), (0xFF46, 0xFF), (0xFF47, 0xFC), (0xFF48, 0xFF), (0xFF49, 0xFF),
+            (0xFF4A, 0x00), (0xFF4B, 0x00),
+            // Boot ROM disabled.
+            (0xFF50, 0x01),
+            // IE.
+            (0xFFFF, 0x00),
+        ]
+    }
+}
@@ -0,0 +1,502 @@
+use constants::*;
+
+const DOTS_OAM_SCAN: u16 = 80;
+const DOTS_DRAWING: u16 = 172;
+const DOTS_PER_LINE: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// LCDC bits.
+const LCDC_ENABLE: u8 = 1 << 7;
+const LCDC_WINDOW_MAP: u8 = 1 << 6;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_BG_MAP: u8 = 1 << 3;
+const LCDC_OBJ_SIZE: u8 = 1 << 2;
+const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+const LCDC_BG_ENABLE: u8 = 1;
+
+// STAT bits.
+const STAT_LYC_INT: u8 = 1 << 6;
+const STAT_OAM_INT: u8 = 1 << 5;
+const STAT_VBLANK_INT: u8 = 1 << 4;
+const STAT_HBLANK_INT: u8 = 1 << 3;
+const STAT_COINCIDENCE: u8 = 1 << 2;
+
+// OAM attribute bits.
+const OBJ_BEHIND_BG: u8 = 1 << 7;
+const OBJ_FLIP_Y: u8 = 1 << 6;
+const OBJ_FLIP_X: u8 = 1 << 5;
+const OBJ_PALETTE: u8 = 1 << 4;
+
+// STAT mode bits (0-1).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    attrs: u8,
+}
+
+pub struct Ppu {
+    vram: Vec<u8>,
+    oam: Vec<u8>,
+
+    // Registers 0xFF40 - 0xFF4B, except DMA.
+    lcdc: u8,
+    // Only the interrupt enable bits (3-6), the rest is derived.
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    mode: Mode,
+    // Dot within the current line (0 - 455).
+    dot: u16,
+    // Internal line counter of the window, only advances on lines the window was drawn on.
+    window_line: u8,
+    lcd_on: bool,
+    // Combined level of the STAT interrupt sources, IF is only requested on its rising edge.
+    stat_line: bool,
+    // Interrupt bits (IF layout) requested since the last step.
+    interrupts: u8,
+    // Dots spent with the LCD off, so frames keep their cadence.
+    off_dots: u32,
+    // One shade (0 - 3, after palette mapping) per pixel.
+    framebuffer: Vec<u8>,
+    frame_ready: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            vram: vec![0; MEM_MAP_VRAM_END - MEM_MAP_VRAM_START + 1],
+            oam: vec![0; MEM_MAP_OAM_END - MEM_MAP_OAM_START + 1],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dot: 0,
+            window_line: 0,
+            lcd_on: false,
+            stat_line: false,
+            interrupts: 0,
+            off_dots: 0,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // True once per completed frame.
+    pub fn take_frame_ready(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    // VRAM is not accessible by the CPU while the PPU is drawing, reads return 0xFF and writes are dropped.
+    pub fn read_vram(&self, addr: usize) -> u8 {
+        if self.mode == Mode::Drawing {
+            return 0xFF;
+        }
+        self.vram[addr - MEM_MAP_VRAM_START]
+    }
+
+    pub fn write_vram(&mut self, addr: usize, byte: u8) {
+        if self.mode != Mode::Drawing {
+            self.vram[addr - MEM_MAP_VRAM_START] = byte;
+        }
+    }
+
+    // OAM is not accessible by the CPU during OAM scan and drawing.
+    pub fn read_oam(&self, addr: usize) -> u8 {
+        if self.is_oam_blocked() {
+            return 0xFF;
+        }
+        self.oam[addr - MEM_MAP_OAM_START]
+    }
+
+    pub fn write_oam(&mut self, addr: usize, byte: u8) {
+        if !self.is_oam_blocked() {
+            self.oam[addr - MEM_MAP_OAM_START] = byte;
+        }
+    }
+
+    // OAM DMA writes regardless of the mode.
+    pub fn write_oam_dma(&mut self, addr: usize, byte: u8) {
+        self.oam[addr - MEM_MAP_OAM_START] = byte;
+    }
+
+    fn is_oam_blocked(&self) -> bool {
+        self.mode == Mode::OamScan || self.mode == Mode::Drawing
+    }
+
+    pub fn read_register(&self, addr: usize) -> u8 {
+        match addr as u16 {
+            REG_LCDC => self.lcdc,
+            REG_STAT => {
+                let coincidence_bit = if self.ly == self.lyc { STAT_COINCIDENCE } else { 0 };
+                self.stat | coincidence_bit | self.mode as u8
+            },
+            REG_SCY => self.scy,
+            REG_SCX => self.scx,
+            REG_LY => self.ly,
+            REG_LYC => self.lyc,
+            REG_BGP => self.bgp,
+            REG_OBP0 => self.obp0,
+            REG_OBP1 => self.obp1,
+            REG_WY => self.wy,
+            REG_WX => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: usize, byte: u8) {
+        match addr as u16 {
+            REG_LCDC => self.lcdc = byte,
+            REG_STAT => self.stat = byte & (STAT_LYC_INT | STAT_OAM_INT | STAT_VBLANK_INT | STAT_HBLANK_INT),
+            REG_SCY => self.scy = byte,
+            REG_SCX => self.scx = byte,
+            // LY is read-only.
+            REG_LY => { },
+            REG_LYC => self.lyc = byte,
+            REG_BGP => self.bgp = byte,
+            REG_OBP0 => self.obp0 = byte,
+            REG_OBP1 => self.obp1 = byte,
+            REG_WY => self.wy = byte,
+            REG_WX => self.wx = byte,
+            _ => { },
+        }
+    }
+
+    // Advances the PPU by the given number of dots (T-cycles), returns the requested interrupts in IF layout.
+    pub fn step(&mut self, cycles: u16) -> u8 {
+        self.interrupts = 0;
+
+        if self.lcdc & LCDC_ENABLE == 0 {
+            self.step_lcd_off(cycles);
+            return self.interrupts;
+        }
+
+        if !self.lcd_on {
+            self.lcd_on = true;
+            self.dot = 0;
+            self.ly = 0;
+            self.window_line = 0;
+            self.mode = Mode::OamScan;
+        }
+
+        for _ in 0..cycles {
+            self.tick();
+        }
+
+        self.interrupts
+    }
+
+    fn step_lcd_off(&mut self, cycles: u16) {
+        if self.lcd_on {
+            self.lcd_on = false;
+            self.dot = 0;
+            self.ly = 0;
+            self.off_dots = 0;
+            self.stat_line = false;
+            self.mode = Mode::HBlank;
+            for pixel in self.framebuffer.iter_mut() {
+                *pixel = 0;
+            }
+        }
+
+        self.off_dots += cycles as u32;
+        if self.off_dots >= DOTS_PER_FRAME {
+            self.off_dots -= DOTS_PER_FRAME;
+            self.frame_ready = true;
+        }
+    }
+
+    fn tick(&mut self) {
+        self.dot += 1;
+
+        match self.mode {
+            Mode::OamScan => if self.dot == DOTS_OAM_SCAN {
+                self.mode = Mode::Drawing;
+            },
+            Mode::Drawing => if self.dot == DOTS_OAM_SCAN + DOTS_DRAWING {
+                self.render_line();
+                self.mode = Mode::HBlank;
+            },
+            Mode::HBlank | Mode::VBlank => if self.dot == DOTS_PER_LINE {
+                self.next_line();
+            },
+        }
+
+        self.update_stat_line();
+    }
+
+    // Requests the LCD STAT interrupt on a rising edge of the combined source line.
+    // While a source holds the line high, other sources can't trigger ("STAT blocking").
+    fn update_stat_line(&mut self) {
+        let line = (self.stat & STAT_LYC_INT != 0 && self.ly == self.lyc) ||
+            (self.stat & STAT_HBLANK_INT != 0 && self.mode == Mode::HBlank) ||
+            (self.stat & STAT_VBLANK_INT != 0 && self.mode == Mode::VBlank) ||
+            // The OAM source also fires when entering V-Blank on line 144.
+            (self.stat & STAT_OAM_INT != 0 && (self.mode == Mode::OamScan || self.mode == Mode::VBlank && self.ly == SCREEN_HEIGHT as u8 && self.dot == 0));
+
+        if line && !self.stat_line {
+            self.interrupts |= 1 << INT_LCD_STAT;
+        }
+        self.stat_line = line;
+    }
+
+    fn next_line(&mut self) {
+        self.dot = 0;
+        self.ly += 1;
+
+        if self.ly == SCREEN_HEIGHT as u8 {
+            self.mode = Mode::VBlank;
+            self.interrupts |= 1 << INT_VBLANK;
+            self.frame_ready = true;
+        } else if self.ly == LINES_PER_FRAME {
+            self.ly = 0;
+            self.window_line = 0;
+            self.mode = Mode::OamScan;
+        } else if self.ly < SCREEN_HEIGHT as u8 {
+            self.mode = Mode::OamScan;
+        }
+    }
+
+    fn render_line(&mut self) {
+        let lcdc = self.lcdc;
+        let bgp = self.bgp;
+        let line_start = self.ly as usize * SCREEN_WIDTH;
+
+        // Color index (before palette) of the background / window, needed for sprite priority.
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+
+        // On DMG, LCDC bit 0 blanks both the background and the window.
+        if lcdc & LCDC_BG_ENABLE != 0 {
+            self.render_background(lcdc, &mut bg_colors);
+            self.render_window(lcdc, &mut bg_colors);
+        }
+
+        for (pixel, color) in self.framebuffer[line_start..line_start + SCREEN_WIDTH].iter_mut().zip(bg_colors.iter()) {
+            *pixel = palette_shade(bgp, *color);
+        }
+
+        if lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(lcdc, &bg_colors);
+        }
+    }
+
+    fn render_background(&self, lcdc: u8, bg_colors: &mut [u8; SCREEN_WIDTH]) {
+        let map = if lcdc & LCDC_BG_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        let y = self.scy.wrapping_add(self.ly);
+
+        for (x, color) in bg_colors.iter_mut().enumerate() {
+            let px = self.scx.wrapping_add(x as u8);
+            *color = tile_map_color(lcdc, &self.vram, map, px, y);
+        }
+    }
+
+    fn render_window(&mut self, lcdc: u8, bg_colors: &mut [u8; SCREEN_WIDTH]) {
+        let wx = self.wx as i16 - 7;
+
+        if lcdc & LCDC_WINDOW_ENABLE == 0 || self.ly < self.wy || wx >= SCREEN_WIDTH as i16 {
+            return;
+        }
+
+        let map = if lcdc & LCDC_WINDOW_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        for x in wx.max(0)..SCREEN_WIDTH as i16 {
+            bg_colors[x as usize] = tile_map_color(lcdc, &self.vram, map, (x - wx) as u8, self.window_line);
+        }
+
+        self.window_line += 1;
+    }
+
+    fn render_sprites(&mut self, lcdc: u8, bg_colors: &[u8; SCREEN_WIDTH]) {
+        let height: i16 = if lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 };
+        let ly = self.ly as i16;
+
+        // OAM scan: the first 10 sprites in OAM order overlapping this line.
+        let mut sprites: Vec<(usize, Sprite)> = (0..OAM_SPRITE_COUNT)
+            .map(|idx| {
+                let entry = &self.oam[idx * 4..idx * 4 + 4];
+                (idx, Sprite {
+                    y: entry[0] as i16 - 16,
+                    x: entry[1] as i16 - 8,
+                    tile: entry[2],
+                    attrs: entry[3],
+                })
+            })
+            .filter(|&(_, sprite)| sprite.y <= ly && ly < sprite.y + height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // DMG priority: smaller X wins, then lower OAM index.
+        sprites.sort_by_key(|&(idx, sprite)| (sprite.x, idx));
+
+        // Winning sprite pixel of each column: color index, palette and its BG priority bit. Only the
+        // highest priority opaque pixel competes with the background, even when the background hides it.
+        let mut pixels: [Option<(u8, u8, bool)>; SCREEN_WIDTH] = [None; SCREEN_WIDTH];
+        for &(_, sprite) in sprites.iter() {
+            let mut row = ly - sprite.y;
+            if sprite.attrs & OBJ_FLIP_Y != 0 {
+                row = height - 1 - row;
+            }
+
+            let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+            let tile_addr = tile as usize * 16 + row as usize * 2;
+            let palette = if sprite.attrs & OBJ_PALETTE != 0 { self.obp1 } else { self.obp0 };
+
+            for col in 0..8i16 {
+                let x = sprite.x + col;
+                if x < 0 || x >= SCREEN_WIDTH as i16 || pixels[x as usize].is_some() {
+                    continue;
+                }
+
+                let bit = if sprite.attrs & OBJ_FLIP_X != 0 { col } else { 7 - col };
+                let color = tile_pixel(self.vram[tile_addr], self.vram[tile_addr + 1], bit as u8);
+                if color == 0 {
+                    continue;
+                }
+
+                pixels[x as usize] = Some((color, palette, sprite.attrs & OBJ_BEHIND_BG != 0));
+            }
+        }
+
+        let line_start = self.ly as usize * SCREEN_WIDTH;
+        for (x, pixel) in pixels.iter().enumerate() {
+            if let Some((color, palette, behind_bg)) = *pixel {
+                if !(behind_bg && bg_colors[x] != 0) {
+                    self.framebuffer[line_start + x] = palette_shade(palette, color);
+                }
+            }
+        }
+    }
+}
+
+// Color index of pixel (x, y) of the 256x256 background plane described by a tile map.
+fn tile_map_color(lcdc: u8, vram: &[u8], map: usize, x: u8, y: u8) -> u8 {
+    let tile_id = vram[map - MEM_MAP_VRAM_START + (y as usize / 8) * 32 + x as usize / 8];
+    let tile_addr = if lcdc & LCDC_TILE_DATA != 0 {
+        tile_id as usize * 16
+    } else {
+        // 0x8800 addressing: signed tile ids relative to 0x9000.
+        (0x1000 + (tile_id as i8 as i32) * 16) as usize
+    };
+    let row_addr = tile_addr + (y as usize % 8) * 2;
+    tile_pixel(vram[row_addr], vram[row_addr + 1], 7 - x % 8)
+}
+
+// Color index of a pixel from the two bitplanes of a tile row.
+fn tile_pixel(lo: u8, hi: u8, bit: u8) -> u8 {
+    (hi >> bit & 1) << 1 | lo >> bit & 1
+}
+
+fn palette_shade(palette: u8, color: u8) -> u8 {
+    palette >> (color * 2) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BG color 1 everywhere, tile 1 is solid color 3 for the sprites.
+    fn ppu_with_sprites(sprites: &[(u8, u8, u8)]) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = LCDC_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE;
+        ppu.bgp = 0xE4;
+        ppu.obp0 = 0xE4;
+        for row in 0..8 {
+            ppu.vram[row * 2] = 0xFF;
+            ppu.vram[16 + row * 2] = 0xFF;
+            ppu.vram[16 + row * 2 + 1] = 0xFF;
+        }
+        for (idx, &(x, tile, attrs)) in sprites.iter().enumerate() {
+            ppu.oam[idx * 4..idx * 4 + 4].copy_from_slice(&[16, x, tile, attrs]);
+        }
+        ppu
+    }
+
+    #[test]
+    fn hidden_sprite_pixel_hides_lower_priority_sprites() {
+        let mut ppu = ppu_with_sprites(&[(8, 1, OBJ_BEHIND_BG), (9, 1, 0)]);
+        ppu.render_line();
+
+        assert_eq!(&ppu.framebuffer[0..10], &[1, 1, 1, 1, 1, 1, 1, 1, 3, 1]);
+    }
+
+    #[test]
+    fn transparent_pixels_let_lower_priority_sprites_through() {
+        // Sprite 0 uses the empty tile 2.
+        let mut ppu = ppu_with_sprites(&[(8, 2, 0), (8, 1, 0)]);
+        ppu.render_line();
+
+        assert_eq!(&ppu.framebuffer[0..9], &[3, 3, 3, 3, 3, 3, 3, 3, 1]);
+    }
+
+    #[test]
+    fn stat_line_held_high_blocks_other_sources() {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = LCDC_ENABLE;
+        ppu.stat = STAT_LYC_INT | STAT_HBLANK_INT;
+
+        // LY = LYC = 0 keeps the line high through the H-Blank of line 0.
+        assert_ne!(ppu.step(4) & 1 << INT_LCD_STAT, 0);
+        assert_eq!(ppu.step(DOTS_PER_LINE - 4) & 1 << INT_LCD_STAT, 0);
+        // Line 1 drops it, so its H-Blank raises the interrupt again.
+        assert_ne!(ppu.step(DOTS_PER_LINE) & 1 << INT_LCD_STAT, 0);
+    }
+
+    #[test]
+    fn lyc_match_raises_the_interrupt_once() {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = LCDC_ENABLE;
+        ppu.stat = STAT_LYC_INT;
+        ppu.lyc = 2;
+
+        let mut raised_on = Vec::new();
+        for _ in 0..LINES_PER_FRAME {
+            if ppu.step(DOTS_PER_LINE) & 1 << INT_LCD_STAT != 0 {
+                raised_on.push(ppu.ly);
+            }
+        }
+        assert_eq!(raised_on, vec![2]);
+    }
+}
@@ -0,0 +1,69 @@
+use constants::*;
+use trace::{Target, Level};
+
+// SC bit 7 starts a transfer, bit 0 selects the internal clock.
+const SC_TRANSFER: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1;
+// 8 bits at 8192 Hz.
+const TRANSFER_CYCLES: u16 = 8 * 512;
+
+// SB and SC (0xFF01 - 0xFF02). There is never a link partner, the shifted in bits are all 1 and every
+// transmitted byte is kept in the output, which is how test ROMs report their results.
+#[derive(Debug, Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    // T-cycles left of the running transfer.
+    remaining: u16,
+    output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial::default()
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    // Advances the given T-cycles, returns true if the serial interrupt is requested.
+    pub fn step(&mut self, cycles: u16) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+
+        self.remaining = self.remaining.saturating_sub(cycles);
+        if self.remaining > 0 {
+            return false;
+        }
+
+        self.sb = 0xFF;
+        self.sc &= !SC_TRANSFER;
+        true
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr as u16 {
+            REG_SB => self.sb,
+            REG_SC => self.sc,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, byte: u8) {
+        match addr as u16 {
+            REG_SB => self.sb = byte,
+            REG_SC => {
+                self.sc = byte & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+                // With the external clock the transfer waits for a partner forever.
+                if self.sc == SC_TRANSFER | SC_INTERNAL_CLOCK {
+                    log!(Target::Io, Level::Debug, "Serial transfer of {:#04x}", self.sb);
+                    self.output.push(self.sb);
+                    self.remaining = TRANSFER_CYCLES;
+                }
+            },
+            _ => { },
+        }
+    }
+}
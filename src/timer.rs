@@ -1,23 +1,22 @@
-use std::collections::HashMap;
+use constants::*;
+use trace::{Target, Level};
 
-#[derive(Debug, Default)]
-struct Ticker {
-    cycle: u64,
-    did_tick: bool,
-}
+// Divider bit whose falling edge clocks TIMA, selected by TAC bits 0-1 (4096, 262144, 65536, 16384 Hz).
+const TAC_DIVIDER_BITS: [u8; 4] = [9, 3, 5, 7];
+const TAC_ENABLE: u8 = 1 << 2;
 
+// DIV, TIMA, TMA and TAC. Every register is derived from or clocked by a 16 bit divider
+// counting T-cycles, DIV being its upper byte.
 #[derive(Debug, Default)]
-struct SequenceTicker {
-    lengths: Vec<u64>,
-    current_phase: usize,
-    current_cycle: u64,
-    did_tick: bool,
-}
-
-#[derive(Default)]
 pub struct Timer {
-    ticks: HashMap<u64, Ticker>,
-    sequences: HashMap<String, SequenceTicker>,
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed in the previous M-cycle and reads 0, the TMA reload happens next.
+    overflow_pending: bool,
+    // TIMA was reloaded in the current M-cycle, TIMA writes are ignored and TMA writes go through.
+    reloading: bool,
 }
 
 impl Timer {
@@ -25,54 +24,156 @@ impl Timer {
         Timer::default()
     }
 
-    pub fn register_tick(&mut self, cycle: u64) {
-        self.ticks.insert(cycle, Ticker::default());
+    // Sets the internal divider directly, used for the post-boot state.
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
     }
 
-    pub fn register_tick_series(&mut self, name: String, lengths: Vec<u64>) {
-        let series_ticker = SequenceTicker {
-            lengths: lengths,
-            .. Default::default()
-        };
-        self.sequences.insert(name, series_ticker);
+    // Advances the timer by the given T-cycles, returns true if the timer interrupt is requested.
+    pub fn step(&mut self, cycles: u16) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            interrupt |= self.tick();
+        }
+        interrupt
+    }
+
+    // One M-cycle.
+    fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+
+        self.reloading = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupt = true;
+        }
+
+        let signal = self.signal();
+        self.divider = self.divider.wrapping_add(4);
+        if signal && !self.signal() {
+            self.inc_tima();
+        }
+
+        interrupt
     }
 
-    pub fn phase_of(&self, name: String) -> usize {
-        self.sequences.get(&name).unwrap().current_phase
+    // TIMA is clocked by the selected divider bit ANDed with the enable bit.
+    fn signal(&self) -> bool {
+        let bit = TAC_DIVIDER_BITS[(self.tac & 0b11) as usize];
+        self.tac & TAC_ENABLE != 0 && self.divider >> bit & 1 == 1
     }
 
-    pub fn did_sequence_tick(&self, name: String) -> bool {
-        self.sequences.get(&name).unwrap().did_tick
+    fn inc_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow_pending = overflow;
+        if overflow {
+            log!(Target::Timer, Level::Debug, "TIMA overflow, reloading {:#04x}", self.tma);
+        }
     }
 
-    pub fn inc(&mut self, curr_cycles: u64) {
-        for (cycle, ticker) in self.ticks.iter_mut() {
-            ticker.cycle += curr_cycles;
-            if ticker.cycle >= *cycle {
-                ticker.cycle = ticker.cycle - cycle + 1;
-                ticker.did_tick = true;
-                println!("TICK {:?}", cycle);
-            }
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr as u16 {
+            REG_DIV => (self.divider >> 8) as u8,
+            REG_TIMA => self.tima,
+            REG_TMA => self.tma,
+            REG_TAC => self.tac,
+            _ => 0xFF,
         }
+    }
 
-        for (_, seq) in self.sequences.iter_mut() {
-            seq.current_cycle += curr_cycles;
-            seq.did_tick = false;
-            while seq.current_cycle > seq.lengths[seq.current_phase] {
-                seq.did_tick = true;
-                seq.current_cycle -= seq.lengths[seq.current_phase];
-                seq.current_phase += 1;
-                if seq.current_phase >= seq.lengths.len() {
-                    seq.current_phase = 0;
+    pub fn write(&mut self, addr: usize, byte: u8) {
+        match addr as u16 {
+            // Resetting the divider can produce a falling edge on the selected bit and clock TIMA.
+            REG_DIV => {
+                let signal = self.signal();
+                self.divider = 0;
+                if signal {
+                    self.inc_tima();
+                }
+            },
+            // Writing in the cycle after the overflow cancels the reload.
+            REG_TIMA if !self.reloading => {
+                self.overflow_pending = false;
+                self.tima = byte;
+            },
+            REG_TMA => {
+                self.tma = byte;
+                if self.reloading {
+                    self.tima = byte;
+                }
+            },
+            // Disabling or switching the frequency can also produce a falling edge.
+            REG_TAC => {
+                let signal = self.signal();
+                self.tac = byte & 0b111;
+                if signal && !self.signal() {
+                    self.inc_tima();
                 }
-            }
+            },
+            _ => { },
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 262144 Hz, TIMA is clocked by the falling edge of divider bit 3, every 16 T-cycles.
+    fn fast_timer(tima: u8, tma: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(REG_TAC as usize, TAC_ENABLE | 0b01);
+        timer.write(REG_TIMA as usize, tima);
+        timer.write(REG_TMA as usize, tma);
+        timer
+    }
+
+    #[test]
+    fn tima_overflow_reloads_tma_one_cycle_later() {
+        let mut timer = fast_timer(0xFF, 0x42);
+
+        assert!(!timer.step(16));
+        assert_eq!(timer.read(REG_TIMA as usize), 0x00);
+        assert!(timer.step(4));
+        assert_eq!(timer.read(REG_TIMA as usize), 0x42);
+    }
+
+    #[test]
+    fn tima_write_after_overflow_cancels_the_reload() {
+        let mut timer = fast_timer(0xFF, 0x42);
+
+        timer.step(16);
+        timer.write(REG_TIMA as usize, 0x10);
+        assert!(!timer.step(4));
+        assert_eq!(timer.read(REG_TIMA as usize), 0x10);
+    }
+
+    #[test]
+    fn div_reset_with_the_selected_bit_high_clocks_tima() {
+        let mut timer = fast_timer(0x00, 0x00);
+
+        timer.step(8);
+        timer.write(REG_DIV as usize, 0);
+        assert_eq!(timer.read(REG_TIMA as usize), 0x01);
+        assert_eq!(timer.read(REG_DIV as usize), 0x00);
+    }
+
+    #[test]
+    fn disabling_tac_with_the_selected_bit_high_clocks_tima() {
+        let mut timer = fast_timer(0x00, 0x00);
+
+        timer.step(8);
+        timer.write(REG_TAC as usize, 0b01);
+        assert_eq!(timer.read(REG_TIMA as usize), 0x01);
 
-    pub fn did_tick(&mut self, cycle: u64) -> bool {
-        let mut ticker = self.ticks.get_mut(&cycle).unwrap();
-        let did_tick = ticker.did_tick;
-        ticker.did_tick = false;
-        did_tick
+        // Divider 8 -> 16 is a regular falling edge, disabling with the bit low does nothing.
+        timer.write(REG_TAC as usize, TAC_ENABLE | 0b01);
+        timer.step(8);
+        assert_eq!(timer.read(REG_TIMA as usize), 0x02);
+        timer.write(REG_TAC as usize, 0b01);
+        assert_eq!(timer.read(REG_TIMA as usize), 0x02);
     }
 }
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Off = 0,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Cpu = 0,
+    Bus,
+    Io,
+    Timer,
+    Interrupts,
+}
+
+const TARGETS: [(Target, &str); 5] = [
+    (Target::Cpu, "cpu"),
+    (Target::Bus, "bus"),
+    (Target::Io, "io"),
+    (Target::Timer, "timer"),
+    (Target::Interrupts, "interrupts"),
+];
+
+// Maximum enabled level per target, everything is off by default.
+static LEVELS: [AtomicUsize; 5] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+pub fn target_name(target: Target) -> &'static str {
+    TARGETS[target as usize].1
+}
+
+#[inline(always)]
+pub fn enabled(target: Target, level: Level) -> bool {
+    LEVELS[target as usize].load(Ordering::Relaxed) >= level as usize
+}
+
+pub fn set_level(target: Target, level: Level) {
+    LEVELS[target as usize].store(level as usize, Ordering::Relaxed);
+}
+
+// Applies a comma separated list of `target[=level]` entries, e.g. "cpu=debug,interrupts".
+// The target `all` addresses every target, a missing level means `trace`.
+pub fn configure(spec: &str) -> Result<(), String> {
+    for entry in spec.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let level = match parts.next() {
+            Some(level_name) => Level::from_name(level_name).ok_or(format!("unknown trace level {}", level_name))?,
+            None => Level::Trace,
+        };
+
+        if name == "all" {
+            for &(target, _) in TARGETS.iter() {
+                set_level(target, level);
+            }
+            continue;
+        }
+
+        match TARGETS.iter().find(|&&(_, target_name)| target_name == name) {
+            Some(&(target, _)) => set_level(target, level),
+            None => return Err(format!("unknown trace target {}", name)),
+        }
+    }
+
+    Ok(())
+}
+
+// Writes a message to stderr if the level is enabled for the target.
+// The arguments are not evaluated when disabled.
+macro_rules! log {
+    ($target:expr, $level:expr, $($arg:tt)+) => (
+        {
+            if $crate::trace::enabled($target, $level) {
+                eprintln!("[{}] {}", $crate::trace::target_name($target), format_args!($($arg)+));
+            }
+        }
+    )
+}
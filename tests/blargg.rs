@@ -0,0 +1,66 @@
+// Runs Blargg's cpu_instrs and instr_timing ROMs, BLARGG_DIR points at a checkout of the gb-test-roms
+// collection. The ROMs print their result over the serial port.
+extern crate gameboy_emu;
+
+mod common;
+
+use std::path::Path;
+use gameboy_emu::constants::CYCLES_PER_SECOND;
+
+// ROM path relative to BLARGG_DIR and its budget in seconds of emulated time.
+const ROMS: [(&str, u64); 12] = [
+    ("cpu_instrs/individual/01-special.gb", 10),
+    ("cpu_instrs/individual/02-interrupts.gb", 10),
+    ("cpu_instrs/individual/03-op sp,hl.gb", 10),
+    ("cpu_instrs/individual/04-op r,imm.gb", 10),
+    ("cpu_instrs/individual/05-op rp.gb", 10),
+    ("cpu_instrs/individual/06-ld r,r.gb", 10),
+    ("cpu_instrs/individual/07-jr,jp,call,ret,rst.gb", 10),
+    ("cpu_instrs/individual/08-misc instrs.gb", 10),
+    ("cpu_instrs/individual/09-op r,r.gb", 20),
+    ("cpu_instrs/individual/10-bit ops.gb", 20),
+    ("cpu_instrs/individual/11-op a,(hl).gb", 30),
+    ("instr_timing/instr_timing.gb", 10),
+];
+
+// Runs until the serial output reports the result, Err carries the output if it failed, stopped or ran out of cycles.
+fn run(path: &Path, budget: u64) -> Result<(), String> {
+    let mut gameboy = common::boot(path)?;
+
+    while gameboy.cycles() < budget * CYCLES_PER_SECOND {
+        gameboy.step().map_err(|err| err.to_string())?;
+
+        let output = String::from_utf8_lossy(gameboy.serial_output());
+        if output.contains("Passed") {
+            return Ok(());
+        }
+        if output.contains("Failed") {
+            // Let the ROM finish listing the failed opcodes.
+            let until = gameboy.cycles() + CYCLES_PER_SECOND;
+            while gameboy.cycles() < until && !gameboy.is_stopped() && gameboy.step().is_ok() { }
+            return Err(String::from_utf8_lossy(gameboy.serial_output()).into_owned());
+        }
+        // Nothing presses a button, the clock would never advance again.
+        if gameboy.is_stopped() {
+            return Err(format!("entered STOP, output: {}", output));
+        }
+    }
+
+    let output = String::from_utf8_lossy(gameboy.serial_output()).into_owned();
+    Err(format!("no result after {} s, output: {}", budget, output))
+}
+
+#[test]
+#[ignore = "needs BLARGG_DIR"]
+fn blargg_test_roms() {
+    let dir = common::rom_dir("BLARGG_DIR");
+
+    let mut failures = Vec::new();
+    for &(name, budget) in ROMS.iter() {
+        if let Err(msg) = run(&dir.join(name), budget) {
+            failures.push(format!("{}: {}", name, msg));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,29 @@
+// Helpers shared by the test ROM harnesses. The ROMs are not redistributable, every harness reads them
+// from a local directory given by an environment variable. The harnesses are ignored by default, run them
+// with `cargo test -- --ignored` once the variables are set.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use gameboy_emu::cartridge::Cartridge;
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::model::Model;
+
+// A harness that was asked to run fails without its ROMs instead of passing without testing anything.
+pub fn rom_dir(var: &str) -> PathBuf {
+    match env::var(var) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => panic!("{} is not set, point it at the test ROMs.", var),
+    }
+}
+
+// Boots the ROM on a DMG without a boot ROM.
+pub fn boot(path: &Path) -> Result<GameBoy, String> {
+    let rom = fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+    let cartridge = Cartridge::new(rom).map_err(|msg| format!("invalid cartridge {}: {}", path.display(), msg))?;
+
+    let mut gameboy = GameBoy::new(None, cartridge, Model::Dmg);
+    gameboy.reset();
+    Ok(gameboy)
+}
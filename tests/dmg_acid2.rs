@@ -0,0 +1,69 @@
+// Runs dmg-acid2 and compares the screen with the reference screenshot, DMG_ACID2_DIR has to contain
+// dmg-acid2.gb and reference-dmg.png. On a mismatch a diff image is written next to the test binaries.
+extern crate gameboy_emu;
+extern crate png;
+
+mod common;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use gameboy_emu::constants::{SCREEN_WIDTH, SCREEN_HEIGHT, SHADE_GRAYSCALE};
+
+// The test draws its final image well before this.
+const FRAMES: usize = 60;
+
+// Decodes the reference into one gray byte per pixel, taking the first channel of color images.
+fn read_reference(path: &Path) -> Vec<u8> {
+    let file = File::open(path).unwrap_or_else(|err| panic!("Cannot open {}: {}", path.display(), err));
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+
+    assert_eq!((info.width as usize, info.height as usize), (SCREEN_WIDTH, SCREEN_HEIGHT),
+               "Reference image has the wrong size");
+
+    let channels = info.color_type.samples();
+    buf[..info.buffer_size()].chunks(channels).map(|pixel| pixel[0]).collect()
+}
+
+// Matching pixels are dimmed, mismatching ones are red.
+fn write_diff(path: &Path, actual: &[u8], expected: &[u8]) {
+    let data: Vec<u8> = actual.iter().zip(expected.iter()).flat_map(|(&a, &e)| {
+        if a == e {
+            let dimmed = 0x80 + a / 2;
+            vec![dimmed, dimmed, dimmed]
+        } else {
+            vec![0xFF, 0x00, 0x00]
+        }
+    }).collect();
+
+    let file = File::create(path).unwrap();
+    let mut encoder = png::Encoder::new(BufWriter::new(file), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
+
+#[test]
+#[ignore = "needs DMG_ACID2_DIR"]
+fn dmg_acid2() {
+    let dir = common::rom_dir("DMG_ACID2_DIR");
+    let mut gameboy = common::boot(&dir.join("dmg-acid2.gb")).unwrap_or_else(|msg| panic!("{}", msg));
+
+    for _ in 0..FRAMES {
+        gameboy.run_frame().unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    let actual: Vec<u8> = gameboy.framebuffer().iter().map(|&shade| SHADE_GRAYSCALE[shade as usize]).collect();
+    let expected = read_reference(&dir.join("reference-dmg.png"));
+
+    let mismatches = actual.iter().zip(expected.iter()).filter(|&(a, e)| a != e).count();
+    if mismatches > 0 {
+        let diff_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("dmg-acid2-diff.png");
+        write_diff(&diff_path, &actual, &expected);
+        panic!("{} pixels differ from the reference, see {}", mismatches, diff_path.display());
+    }
+}
@@ -0,0 +1,86 @@
+// Runs the mooneye-test-suite acceptance ROMs, MOONEYE_DIR points at the built acceptance directory.
+// A ROM signals the end with LD B,B, having the Fibonacci numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L
+// when it passed.
+extern crate gameboy_emu;
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use gameboy_emu::constants::CYCLES_PER_SECOND;
+
+// Every acceptance test finishes well within this, in seconds of emulated time.
+const BUDGET: u64 = 10;
+
+// Test names end with the models they are meant for, e.g. di_timing-GS or boot_regs-dmgABC.
+// Only the ones for the emulated DMG (revisions A, B, C) run.
+fn runs_on_dmg(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+    let models = match stem.rfind('-') {
+        Some(idx) => &stem[idx + 1..],
+        None => return true,
+    };
+
+    if models.starts_with("dmg") {
+        models.contains("ABC")
+    } else if models.chars().all(|c| c.is_ascii_uppercase()) {
+        models.contains('G')
+    } else {
+        false
+    }
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap_or_else(|err| panic!("Cannot read {}: {}", dir.display(), err)) {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_roms(&path, roms);
+        } else if path.extension().map(|ext| ext == "gb").unwrap_or(false) && runs_on_dmg(&path) {
+            roms.push(path);
+        }
+    }
+}
+
+fn run(path: &Path) -> Result<(), String> {
+    let mut gameboy = common::boot(path)?;
+
+    while gameboy.cycles() < BUDGET * CYCLES_PER_SECOND {
+        gameboy.step().map_err(|err| err.to_string())?;
+
+        if gameboy.take_breakpoint() {
+            let regs = gameboy.registers();
+            let signature = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+            return if signature == [3, 5, 8, 13, 21, 34] {
+                Ok(())
+            } else {
+                Err(format!("failed with B, C, D, E, H, L = {:02X?}", signature))
+            };
+        }
+        // Nothing presses a button, the clock would never advance again.
+        if gameboy.is_stopped() {
+            return Err("entered STOP".to_string());
+        }
+    }
+
+    Err(format!("no breakpoint after {} s", BUDGET))
+}
+
+#[test]
+#[ignore = "needs MOONEYE_DIR"]
+fn mooneye_acceptance() {
+    let dir = common::rom_dir("MOONEYE_DIR");
+
+    let mut roms = Vec::new();
+    collect_roms(&dir, &mut roms);
+    roms.sort();
+    assert!(!roms.is_empty(), "No test ROMs found in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for rom in roms.iter() {
+        if let Err(msg) = run(rom) {
+            failures.push(format!("{}: {}", rom.strip_prefix(&dir).unwrap().display(), msg));
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} ROMs failed:\n{}", failures.len(), roms.len(), failures.join("\n"));
+}
@@ -0,0 +1,147 @@
+// Runs the SingleStepTests sm83 vectors (https://github.com/SingleStepTests/sm83) against the CPU.
+// Point SM83_TESTS_DIR at the directory holding 00.json - ff.json and cb 00.json - cb ff.json and run
+// with `cargo test -- --ignored`.
+extern crate gameboy_emu;
+extern crate json;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use gameboy_emu::bus::Memory;
+use gameboy_emu::cpu::{CPU, Registers};
+use json::JsonValue;
+
+const MAX_REPORTED_FAILURES: usize = 20;
+
+// 64 KiB of plain RAM without any hardware behind it, the vectors assume nothing is mapped.
+struct FlatMemory {
+    ram: Vec<u8>,
+    cycles: u64,
+}
+
+impl Memory for FlatMemory {
+    fn read_byte(&self, addr: usize) -> u8 {
+        self.ram[addr]
+    }
+
+    fn write_byte(&mut self, addr: usize, byte: u8) {
+        self.ram[addr] = byte;
+    }
+
+    fn register_cycles(&mut self, cycles: u16) {
+        self.cycles += cycles as u64;
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn is_any_joypad_line_low(&self) -> bool {
+        false
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+}
+
+fn registers(state: &JsonValue) -> Registers {
+    Registers {
+        a: state["a"].as_u8().unwrap(),
+        f: state["f"].as_u8().unwrap(),
+        b: state["b"].as_u8().unwrap(),
+        c: state["c"].as_u8().unwrap(),
+        d: state["d"].as_u8().unwrap(),
+        e: state["e"].as_u8().unwrap(),
+        h: state["h"].as_u8().unwrap(),
+        l: state["l"].as_u8().unwrap(),
+        sp: state["sp"].as_u16().unwrap(),
+        pc: state["pc"].as_u16().unwrap(),
+    }
+}
+
+// Executes one instruction from the initial state and lists every difference to the final state.
+fn run_case(case: &JsonValue) -> Result<(), String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut bus = FlatMemory { ram: vec![0; 0x1_0000], cycles: 0 };
+    for entry in initial["ram"].members() {
+        bus.write_byte(entry[0].as_usize().unwrap(), entry[1].as_u8().unwrap());
+    }
+
+    let mut cpu = CPU::new();
+    cpu.set_registers(registers(initial));
+    cpu.set_ime(initial["ime"].as_u8() == Some(1));
+
+    let start_cycles = bus.cycles();
+    cpu.next_instruction(&mut bus).map_err(|err| err.to_string())?;
+
+    let mut errors = Vec::new();
+
+    let actual = cpu.registers();
+    let wanted = registers(expected);
+    if actual != wanted {
+        errors.push(format!("registers {:?}, expected {:?}", actual, wanted));
+    }
+
+    if let Some(ime) = expected["ime"].as_u8() {
+        if cpu.ime() != (ime == 1) {
+            errors.push(format!("IME {}, expected {}", cpu.ime(), ime == 1));
+        }
+    }
+
+    for entry in expected["ram"].members() {
+        let addr = entry[0].as_usize().unwrap();
+        let byte = entry[1].as_u8().unwrap();
+        let actual = bus.read_byte(addr);
+        if actual != byte {
+            errors.push(format!("({:#06x}) = {:#04x}, expected {:#04x}", addr, actual, byte));
+        }
+    }
+
+    let cycles = bus.cycles() - start_cycles;
+    let wanted_cycles = case["cycles"].len() as u64 * 4;
+    if cycles != wanted_cycles {
+        errors.push(format!("{} cycles, expected {}", cycles, wanted_cycles));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+#[test]
+#[ignore = "needs SM83_TESTS_DIR"]
+fn sm83_single_step_tests() {
+    let dir = env::var("SM83_TESTS_DIR").expect("SM83_TESTS_DIR is not set, point it at the test vectors.");
+
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("Cannot read {}: {}", dir, err))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "No test vectors found in {}", dir);
+
+    let mut cases = 0;
+    let mut failures = Vec::new();
+    for path in files {
+        let content = fs::read_to_string(&path).unwrap();
+        let vectors = json::parse(&content).unwrap_or_else(|err| panic!("Cannot parse {}: {}", path.display(), err));
+
+        for case in vectors.members() {
+            cases += 1;
+            if let Err(msg) = run_case(case) {
+                failures.push(format!("{}: {}", case["name"], msg));
+            }
+        }
+    }
+
+    for msg in failures.iter().take(MAX_REPORTED_FAILURES) {
+        eprintln!("{}", msg);
+    }
+    assert!(failures.is_empty(), "{} of {} cases failed", failures.len(), cases);
+}